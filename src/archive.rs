@@ -0,0 +1,151 @@
+use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+
+/// Names of entries written for each customer, as `/` separated templates.
+///
+/// Placeholders: `{customer}` is the sanitised customer name, `{section}` the zero based
+/// promo section index and `{promo}` the one based promo number shown in the reports.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ArchiveLayout {
+    pub missing_report: String,
+    pub detail: String,
+}
+
+impl Default for ArchiveLayout {
+    fn default() -> Self {
+        Self {
+            missing_report: "Missing_Reports/{customer} Missing Report.pdf".to_owned(),
+            detail: "{customer}/Promo#{section}.pdf".to_owned(),
+        }
+    }
+}
+
+impl ArchiveLayout {
+    pub fn missing_report_path(&self, customer: &str) -> String {
+        render_template(&self.missing_report, customer, None)
+    }
+    pub fn detail_path(&self, customer: &str, section_index: usize) -> String {
+        render_template(&self.detail, customer, Some(section_index))
+    }
+}
+
+/// Fills the placeholders of `template` in one pass, so text a placeholder puts in (such as a
+/// customer name with braces) is never read as another placeholder.
+fn render_template(template: &str, customer: &str, section_index: Option<usize>) -> String {
+    let template = template.replace('\\', "/");
+    let mut path = String::with_capacity(template.len());
+    let mut rest = template.as_str();
+    while let Some(open) = rest.find('{') {
+        path.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let placeholder = rest.find('}').and_then(|close| {
+            let value = match (&rest[1..close], section_index) {
+                ("customer", _) => customer.to_owned(),
+                ("section", Some(section_index)) => section_index.to_string(),
+                ("promo", Some(section_index)) => (section_index + 1).to_string(),
+                _ => return None,
+            };
+            Some((value, close))
+        });
+        match placeholder {
+            Some((value, close)) => {
+                path.push_str(&value);
+                rest = &rest[close + 1..];
+            }
+            None => {
+                path.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    path.push_str(rest);
+    path
+}
+
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Makes a customer name usable as a single path component on Windows, Linux and macOS.
+pub fn sanitize_file_name(name: &str) -> String {
+    let replaced: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let trimmed = replaced.trim_start().trim_end_matches(|c: char| c == '.' || c.is_whitespace());
+    if trimmed.is_empty() {
+        return "_".to_owned();
+    }
+    let stem = trimmed.split('.').next().unwrap_or("");
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        format!("_{}", trimmed)
+    } else {
+        trimmed.to_owned()
+    }
+}
+
+/// Maps each customer to a sanitised name that is unique ignoring case.
+///
+/// Customers are visited in sorted order so that a name which collides after sanitising
+/// gets the same ` (2)`, ` (3)` suffix on every run.
+pub fn unique_customer_names<'a, I: IntoIterator<Item = &'a String>>(customers: I) -> HashMap<String, String> {
+    let mut sorted: Vec<&String> = customers.into_iter().collect();
+    sorted.sort();
+    let mut taken: HashSet<String> = HashSet::new();
+    let mut rv = HashMap::new();
+    for customer in sorted {
+        let base = sanitize_file_name(customer);
+        let mut candidate = base.clone();
+        let mut suffix = 2;
+        while !taken.insert(candidate.to_lowercase()) {
+            candidate = format!("{} ({})", base, suffix);
+            suffix += 1;
+        }
+        rv.insert(customer.clone(), candidate);
+    }
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_in_a_customer_name_are_kept() {
+        let layout = ArchiveLayout { detail: "{customer}\\{promo} of {customer}.pdf".to_owned(), ..ArchiveLayout::default() };
+        assert_eq!(layout.detail_path("Acme {promo}", 0), "Acme {promo}/1 of Acme {promo}.pdf");
+        assert_eq!(layout.missing_report_path("{section}"), "Missing_Reports/{section} Missing Report.pdf");
+        let unknown = ArchiveLayout { missing_report: "{year}/{customer}{".to_owned(), ..ArchiveLayout::default() };
+        assert_eq!(unknown.missing_report_path("Acme"), "{year}/Acme{");
+    }
+
+    #[test]
+    fn file_names_are_portable() {
+        assert_eq!(sanitize_file_name("A<b>c:d\"e/f\\g|h?i*j"), "A_b_c_d_e_f_g_h_i_j");
+        assert_eq!(sanitize_file_name("Tab\there"), "Tab_here");
+        assert_eq!(sanitize_file_name("  Acme Inc. . "), "Acme Inc");
+        assert_eq!(sanitize_file_name("..."), "_");
+        assert_eq!(sanitize_file_name("CON"), "_CON");
+        assert_eq!(sanitize_file_name("com1.txt"), "_com1.txt");
+        assert_eq!(sanitize_file_name("Console"), "Console");
+    }
+
+    #[test]
+    fn suffixes_do_not_depend_on_input_order() {
+        let customers: Vec<String> = vec!["Acme".to_owned(), "ACME".to_owned(), "Acme (2)".to_owned()];
+        let names = unique_customer_names(&customers);
+        assert_eq!(names["ACME"], "ACME");
+        assert_eq!(names["Acme"], "Acme (2)");
+        assert_eq!(names["Acme (2)"], "Acme (2) (2)");
+
+        let reversed: Vec<String> = customers.iter().rev().cloned().collect();
+        assert_eq!(unique_customer_names(&reversed), names);
+    }
+}
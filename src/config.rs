@@ -0,0 +1,454 @@
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::error::PromoFinError;
+use crate::archive::ArchiveLayout;
+use crate::encryption::EncryptionOptions;
+use crate::metadata::DocumentMetadata;
+use crate::near_miss::NearMissOptions;
+use crate::payout::PayoutRules;
+use crate::pdfa::ArchivalOptions;
+use crate::theme::Theme;
+use crate::xlsx::SheetPer;
+
+/// Everything needed to reproduce a promo period's run: inputs, outputs, layout and which reports to emit.
+///
+/// Loaded from a `.toml` or `.json` file with [`RunConfig::from_file`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunConfig {
+    /// Sales export to evaluate.
+    pub input_file: String,
+    /// Promotion definition json.
+    pub promo_file: String,
+    /// Csv of `Part Number` and `Price` used before historical sale prices when recommending
+    /// the cheapest purchases.
+    #[serde(default)]
+    pub price_list: Option<String>,
+    /// Reward per qualification of each promo section, for the payout register.
+    #[serde(default)]
+    pub payouts: PayoutRules,
+    /// Ranking and filter of the near-miss report.
+    #[serde(default)]
+    pub near_miss: NearMissOptions,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub layout: LayoutConfig,
+    #[serde(default)]
+    pub reports: ReportSelection,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// Combined missing report for all customers.
+    pub missing_report_pdf: Option<String>,
+    /// Missing report model as json for importers.
+    pub missing_report_json: Option<String>,
+    /// Missing report as csv for spreadsheets.
+    pub missing_report_csv: Option<String>,
+    /// Qualifying purchase detail as an xlsx workbook.
+    pub detail_xlsx: Option<String>,
+    /// Whether the workbook gets one sheet per customer or per promo section.
+    pub detail_xlsx_sheets: SheetPer,
+    /// Folder receiving a static html site of the missing and detail reports.
+    pub html_directory: Option<String>,
+    /// Rewards owed per customer, for accounting.
+    pub payout_register_pdf: Option<String>,
+    pub payout_register_csv: Option<String>,
+    /// Customers closest to their next qualification, a page per rep.
+    pub near_miss_pdf: Option<String>,
+    pub near_miss_csv: Option<String>,
+    /// Zip holding the per-customer missing reports and qualifying detail.
+    pub zip: Option<String>,
+    /// Folder receiving the same per-customer files as a plain directory tree.
+    pub directory: Option<String>,
+    /// Entry names used inside the zip.
+    pub archive_layout: ArchiveLayout,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            missing_report_pdf: Some("Missing Report.pdf".to_owned()),
+            missing_report_json: None,
+            missing_report_csv: None,
+            detail_xlsx: None,
+            detail_xlsx_sheets: SheetPer::default(),
+            html_directory: None,
+            payout_register_pdf: None,
+            payout_register_csv: None,
+            near_miss_pdf: None,
+            near_miss_csv: None,
+            zip: Some("promo.zip".to_owned()),
+            directory: None,
+            archive_layout: ArchiveLayout::default(),
+        }
+    }
+}
+
+/// Paper sizes a report can be printed on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PaperSize {
+    Letter,
+    Legal,
+    A4,
+    /// Any other sheet, given portrait side up.
+    Custom { width_inch: f64, height_inch: f64 },
+}
+
+impl PaperSize {
+    /// Width and height in inches with the short side first.
+    pub fn portrait_inches(&self) -> (f64, f64) {
+        let (a, b) = match *self {
+            PaperSize::Letter => (8.5, 11.0),
+            PaperSize::Legal => (8.5, 14.0),
+            PaperSize::A4 => (210.0 / 25.4, 297.0 / 25.4),
+            PaperSize::Custom { width_inch, height_inch } => (width_inch, height_inch),
+        };
+        if a <= b { (a, b) } else { (b, a) }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Orientation {
+    Portrait,
+    Landscape,
+}
+
+/// Page setup for a single report type, in inches.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PageLayout {
+    pub paper: PaperSize,
+    pub orientation: Orientation,
+    pub dpi: f64,
+    pub margin_top: f64,
+    pub margin_bottom: f64,
+}
+
+impl PageLayout {
+    pub fn portrait() -> Self {
+        Self { paper: PaperSize::Letter, orientation: Orientation::Portrait, dpi: 72.0, margin_top: 0.25, margin_bottom: 0.25 }
+    }
+    pub fn landscape() -> Self {
+        Self { orientation: Orientation::Landscape, ..Self::portrait() }
+    }
+    pub fn width_inch(&self) -> f64 {
+        match self.orientation {
+            Orientation::Portrait => self.paper.portrait_inches().0,
+            Orientation::Landscape => self.paper.portrait_inches().1,
+        }
+    }
+    pub fn height_inch(&self) -> f64 {
+        match self.orientation {
+            Orientation::Portrait => self.paper.portrait_inches().1,
+            Orientation::Landscape => self.paper.portrait_inches().0,
+        }
+    }
+}
+
+impl Default for PageLayout {
+    fn default() -> Self {
+        Self::portrait()
+    }
+}
+
+/// Font sizes in points shared by the report writers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct FontSizes {
+    /// Customer name at the top of a missing report.
+    pub title: f64,
+    /// Section headings and the detail report's quantity totals.
+    pub heading: f64,
+    /// Part number grids and the detail table header.
+    pub body: f64,
+    /// Detail table rows and header lines.
+    pub small: f64,
+}
+
+impl Default for FontSizes {
+    fn default() -> Self {
+        Self { title: 16.0, heading: 14.0, body: 12.0, small: 10.0 }
+    }
+}
+
+impl FontSizes {
+    pub fn size(&self, style: TextStyle) -> f64 {
+        match style {
+            TextStyle::Title => self.title,
+            TextStyle::Heading => self.heading,
+            TextStyle::Body => self.body,
+            TextStyle::Small => self.small,
+        }
+    }
+}
+
+/// The text styles of the reports, each with a size in [`FontSizes`] and a face in [`FontFaces`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextStyle {
+    Title,
+    Heading,
+    Body,
+    Small,
+}
+
+impl TextStyle {
+    pub const ALL: [TextStyle; 4] = [TextStyle::Title, TextStyle::Heading, TextStyle::Body, TextStyle::Small];
+}
+
+/// TrueType or OpenType files embedded in place of Helvetica, per text style.
+///
+/// Styles without a face of their own use `default`; when nothing is set the reports keep
+/// the standard Helvetica. Only the glyphs a document shows are embedded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct FontFaces {
+    /// Also used for running headers, footers and the brand title.
+    pub default: Option<String>,
+    pub title: Option<String>,
+    pub heading: Option<String>,
+    pub body: Option<String>,
+    pub small: Option<String>,
+}
+
+impl FontFaces {
+    /// The face set for `style` itself, without falling back to `default`.
+    pub fn face(&self, style: TextStyle) -> &Option<String> {
+        match style {
+            TextStyle::Title => &self.title,
+            TextStyle::Heading => &self.heading,
+            TextStyle::Body => &self.body,
+            TextStyle::Small => &self.small,
+        }
+    }
+}
+
+/// Text drawn on the left, centre and right of a running header or footer.
+///
+/// Placeholders: `{customer}`, `{section}`, `{period}`, `{page}` and `{pages}` for the whole
+/// document, and `{customer_page}` and `{customer_pages}` counted within the current customer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct RunningText {
+    pub left: String,
+    pub center: String,
+    pub right: String,
+}
+
+/// Running header and footer drawn on every page, in a band added inside the page margins.
+/// Neither is drawn unless configured, so existing layouts keep their pages unchanged.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PageDecorations {
+    pub header: Option<RunningText>,
+    pub footer: Option<RunningText>,
+    pub font_size: f64,
+    /// Promotion period shown through `{period}`, e.g. "May 1–Jul 31 2020".
+    pub promo_period: String,
+}
+
+impl Default for PageDecorations {
+    fn default() -> Self {
+        Self {
+            header: None,
+            footer: None,
+            font_size: 8.0,
+            promo_period: String::new(),
+        }
+    }
+}
+
+/// How the grouped sections of a report (one promo section, one detail table) are set apart.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SectionStyle {
+    Plain,
+    /// Tinted background with a thin border, coloured from the theme.
+    Card,
+}
+
+impl Default for SectionStyle {
+    fn default() -> Self {
+        SectionStyle::Plain
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct RenderOptions {
+    /// Outlines every placement group in a debug colour instead of the section style,
+    /// for checking the layout. Never meant for customer copies.
+    pub debug_layout: bool,
+    pub section_style: SectionStyle,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LayoutConfig {
+    /// Combined and per-customer missing reports.
+    pub missing_page: PageLayout,
+    /// Qualifying purchase detail report.
+    pub detail_page: PageLayout,
+    pub fonts: FontSizes,
+    pub font_faces: FontFaces,
+    pub decorations: PageDecorations,
+    pub theme: Theme,
+    pub render: RenderOptions,
+    pub metadata: DocumentMetadata,
+    /// PDF/A-2b output for every report PDF.
+    pub archival: ArchivalOptions,
+    /// Passwords on the per-customer missing and detail PDFs.
+    pub encryption: EncryptionOptions,
+}
+
+impl Default for LayoutConfig {
+    fn default() -> Self {
+        Self {
+            missing_page: PageLayout::portrait(),
+            detail_page: PageLayout::landscape(),
+            fonts: FontSizes::default(),
+            font_faces: FontFaces::default(),
+            decorations: PageDecorations::default(),
+            theme: Theme::default(),
+            render: RenderOptions::default(),
+            metadata: DocumentMetadata::default(),
+            archival: ArchivalOptions::default(),
+            encryption: EncryptionOptions::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ReportSelection {
+    /// Combined missing report written to `output.missing_report_pdf`.
+    pub combined_missing: bool,
+    /// Per-customer missing reports in the zip.
+    pub customer_missing: bool,
+    /// Per-section qualifying detail in the zip.
+    pub detail: bool,
+}
+
+impl Default for ReportSelection {
+    fn default() -> Self {
+        Self { combined_missing: true, customer_missing: true, detail: true }
+    }
+}
+
+impl RunConfig {
+    /// Reads a run config, choosing the format from the file extension (`.json`, otherwise toml).
+    /// Relative paths in it are taken from the config's own folder.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, PromoFinError> {
+        let path = path.as_ref();
+        let config_err = |message: String| PromoFinError::Config { path: path.display().to_string(), message };
+        let text = std::fs::read_to_string(path).map_err(|e| config_err(e.to_string()))?;
+        let is_json = path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("json"));
+        let mut config: Self = if is_json {
+            serde_json::from_str(&text).map_err(|e| config_err(e.to_string()))?
+        } else {
+            toml::from_str(&text).map_err(|e| config_err(e.to_string()))?
+        };
+        config.resolve_paths(path.parent().unwrap_or_else(|| Path::new("")));
+        Ok(config)
+    }
+
+    /// Makes every relative file and folder in the config relative to `base` instead of
+    /// the working directory, so a run config can sit next to the files it names.
+    fn resolve_paths(&mut self, base: &Path) {
+        resolve_path(base, &mut self.input_file);
+        resolve_path(base, &mut self.promo_file);
+        resolve_optional_path(base, &mut self.price_list);
+        resolve_optional_path(base, &mut self.near_miss.reps_csv);
+
+        let output = &mut self.output;
+        for file in [
+            &mut output.missing_report_pdf,
+            &mut output.missing_report_json,
+            &mut output.missing_report_csv,
+            &mut output.detail_xlsx,
+            &mut output.html_directory,
+            &mut output.payout_register_pdf,
+            &mut output.payout_register_csv,
+            &mut output.near_miss_pdf,
+            &mut output.near_miss_csv,
+            &mut output.zip,
+            &mut output.directory,
+        ] {
+            resolve_optional_path(base, file);
+        }
+
+        let layout = &mut self.layout;
+        for file in [
+            &mut layout.font_faces.default,
+            &mut layout.font_faces.title,
+            &mut layout.font_faces.heading,
+            &mut layout.font_faces.body,
+            &mut layout.font_faces.small,
+            &mut layout.theme.logo,
+            &mut layout.archival.icc_profile,
+            &mut layout.encryption.passwords_csv,
+        ] {
+            resolve_optional_path(base, file);
+        }
+    }
+}
+
+fn resolve_path(base: &Path, file: &mut String) {
+    if !file.is_empty() && Path::new(file.as_str()).is_relative() {
+        *file = base.join(file.as_str()).to_string_lossy().into_owned();
+    }
+}
+
+fn resolve_optional_path(base: &Path, file: &mut Option<String>) {
+    if let Some(file) = file {
+        resolve_path(base, file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paper_is_measured_short_side_first_and_turned_by_orientation() {
+        assert_eq!(PaperSize::Legal.portrait_inches(), (8.5, 14.0));
+        let (a4_width, a4_height) = PaperSize::A4.portrait_inches();
+        assert!((a4_width - 8.2677).abs() < 0.0001 && (a4_height - 11.6929).abs() < 0.0001);
+        assert_eq!(PaperSize::Custom { width_inch: 12.0, height_inch: 9.0 }.portrait_inches(), (9.0, 12.0));
+
+        assert_eq!(PageLayout::portrait().width_inch(), 8.5);
+        assert_eq!(PageLayout::landscape().width_inch(), 11.0);
+        assert_eq!(PageLayout::landscape().height_inch(), 8.5);
+        let legal = PageLayout { paper: PaperSize::Legal, ..PageLayout::landscape() };
+        assert_eq!((legal.width_inch(), legal.height_inch()), (14.0, 8.5));
+    }
+
+    #[test]
+    fn relative_paths_are_resolved_against_the_config_folder() {
+        let folder = std::env::temp_dir().join(format!("promo_fin_config_{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let config_path = folder.join("promo_run.toml");
+        let absolute_promo = folder.join("promo.json");
+        std::fs::write(
+            &config_path,
+            format!(
+                "input_file = \"sales/may.csv\"\npromo_file = {:?}\n\n[output]\ndirectory = \"out\"\n\n[layout.theme]\nlogo = \"logo.png\"\n",
+                absolute_promo.to_str().unwrap()
+            ),
+        )
+        .unwrap();
+        let config = RunConfig::from_file(&config_path).unwrap();
+        std::fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!(Path::new(&config.input_file), folder.join("sales/may.csv"));
+        assert_eq!(Path::new(&config.promo_file), absolute_promo);
+        assert_eq!(config.output.directory.as_ref().map(Path::new), Some(folder.join("out").as_path()));
+        // defaults name files too, and land next to the config like everything else
+        assert_eq!(config.output.zip.as_ref().map(Path::new), Some(folder.join("promo.zip").as_path()));
+        assert_eq!(config.layout.theme.logo.as_ref().map(Path::new), Some(folder.join("logo.png").as_path()));
+        assert_eq!(config.price_list, None);
+    }
+}
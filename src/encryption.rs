@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockEncryptMut, KeyIvInit};
+use lopdf::{dictionary, Object, ObjectId, StringFormat};
+use serde::{Deserialize, Serialize};
+use crate::error::PromoFinError;
+
+/// Password protection for the per-customer PDFs, using the standard security handler with
+/// 128 bit AES (revision 4, `AESV2`).
+///
+/// The user password opening a customer's documents comes from `passwords_csv` (columns
+/// `Customer` and `Password`, e.g. exported account numbers) or else from `password_template`
+/// with `{customer}` filled in. A template must hold a part only the customer is told, since
+/// anyone who knows the template and a customer name could otherwise open that customer's
+/// files. A customer with neither is an error rather than an unprotected file.
+///
+/// The owner password unlocks everything the permissions withhold. When none is set each
+/// document gets a random one that is never stored, so the permissions cannot be lifted.
+///
+/// The csv is read once per run by [`EncryptionOptions::load_passwords`]; every document
+/// built from these options afterwards looks its customer up in that map.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct EncryptionOptions {
+    pub enabled: bool,
+    pub passwords_csv: Option<String>,
+    pub password_template: Option<String>,
+    pub owner_password: String,
+    pub allow_print: bool,
+    pub allow_copy: bool,
+    pub allow_edit: bool,
+    #[serde(skip)]
+    passwords: Option<Rc<HashMap<String, String>>>,
+}
+
+impl Default for EncryptionOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            passwords_csv: None,
+            password_template: None,
+            owner_password: String::new(),
+            allow_print: true,
+            allow_copy: false,
+            allow_edit: false,
+            passwords: None,
+        }
+    }
+}
+
+impl EncryptionOptions {
+    /// Reads `passwords_csv` when encryption is enabled, unless it was already read.
+    pub fn load_passwords(&mut self) -> Result<(), PromoFinError> {
+        if !self.enabled || self.passwords.is_some() {
+            return Ok(());
+        }
+        if let Some(path) = &self.passwords_csv {
+            self.passwords = Some(Rc::new(read_passwords(path)?));
+        }
+        Ok(())
+    }
+
+    /// The user password for `customer`'s documents.
+    pub fn user_password(&self, customer: &str) -> Result<String, PromoFinError> {
+        let passwords = match (&self.passwords, &self.passwords_csv) {
+            (Some(passwords), _) => Some(passwords.clone()),
+            (None, Some(path)) => Some(Rc::new(read_passwords(path)?)),
+            (None, None) => None,
+        };
+        if let Some(password) = passwords.as_ref().and_then(|passwords| passwords.get(customer.trim())) {
+            return Ok(password.clone());
+        }
+        match &self.password_template {
+            Some(template) => Ok(template.replace("{customer}", customer)),
+            None => Err(PromoFinError::Encryption {
+                customer: customer.to_owned(),
+                message: "no password in the passwords csv and no password template".to_owned(),
+            }),
+        }
+    }
+
+    /// The passwords protecting `customer`'s documents, with a random owner password when none
+    /// is configured.
+    pub fn passwords(&self, customer: &str) -> Result<DocumentPasswords, PromoFinError> {
+        let owner = if self.owner_password.is_empty() {
+            random_bytes::<16>(customer)?.iter().map(|b| format!("{:02x}", b)).collect()
+        } else {
+            self.owner_password.clone()
+        };
+        Ok(DocumentPasswords { customer: customer.to_owned(), user: self.user_password(customer)?, owner })
+    }
+
+    /// The `P` entry: reserved bits set, plus the operations allowed without the owner password.
+    fn permissions(&self) -> i32 {
+        let mut bits: u32 = 0xffff_f0c0;
+        if self.allow_print {
+            bits |= 4 | 2048;
+        }
+        if self.allow_edit {
+            bits |= 8 | 32 | 256 | 1024;
+        }
+        if self.allow_copy {
+            bits |= 16;
+        }
+        // text extraction for accessibility tools is always allowed
+        bits |= 512;
+        bits as i32
+    }
+}
+
+/// The user and owner password of one customer's documents.
+#[derive(Debug, Clone)]
+pub struct DocumentPasswords {
+    customer: String,
+    user: String,
+    owner: String,
+}
+
+fn random_bytes<const N: usize>(customer: &str) -> Result<[u8; N], PromoFinError> {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes).map_err(|e| PromoFinError::Encryption {
+        customer: customer.to_owned(),
+        message: format!("no random numbers: {}", e),
+    })?;
+    Ok(bytes)
+}
+
+fn read_passwords(path: &str) -> Result<HashMap<String, String>, PromoFinError> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h.trim().eq_ignore_ascii_case(name));
+    let (customer, password) = match (column("Customer"), column("Password")) {
+        (Some(customer), Some(password)) => (customer, password),
+        _ => {
+            return Err(PromoFinError::Config {
+                path: path.to_owned(),
+                message: "passwords csv needs Customer and Password columns".to_owned(),
+            })
+        }
+    };
+    let mut passwords = HashMap::new();
+    for record in reader.records() {
+        let record = record?;
+        if let (Some(name), Some(secret)) = (record.get(customer), record.get(password)) {
+            passwords.insert(name.trim().to_owned(), secret.to_owned());
+        }
+    }
+    Ok(passwords)
+}
+
+const PADDING: [u8; 32] = [
+    0x28, 0xbf, 0x4e, 0x5e, 0x4e, 0x75, 0x8a, 0x41, 0x64, 0x00, 0x4e, 0x56, 0xff, 0xfa, 0x01, 0x08,
+    0x2e, 0x2e, 0x00, 0xb6, 0xd0, 0x68, 0x3e, 0x80, 0x2f, 0x0c, 0xa9, 0xfe, 0x64, 0x53, 0x69, 0x7a,
+];
+
+fn padded(password: &str) -> Vec<u8> {
+    let bytes: Vec<u8> = password.bytes().take(32).collect();
+    let mut out = bytes.clone();
+    out.extend_from_slice(&PADDING[..32 - bytes.len()]);
+    out
+}
+
+fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut state: Vec<u8> = (0..=255).collect();
+    let mut j: u8 = 0;
+    for i in 0..256 {
+        j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+        state.swap(i, j as usize);
+    }
+    let (mut i, mut j) = (0u8, 0u8);
+    data.iter()
+        .map(|byte| {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(state[i as usize]);
+            state.swap(i as usize, j as usize);
+            byte ^ state[state[i as usize].wrapping_add(state[j as usize]) as usize]
+        })
+        .collect()
+}
+
+/// RC4 with `key`, then 19 more passes with each key byte xor'd with the pass number.
+fn rc4_rounds(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut out = rc4(key, data);
+    for round in 1..=19u8 {
+        let round_key: Vec<u8> = key.iter().map(|k| k ^ round).collect();
+        out = rc4(&round_key, &out);
+    }
+    out
+}
+
+/// MD5 of `data` rehashed 50 times, as revisions 3 and 4 strengthen their keys.
+fn md5_rounds(data: &[u8]) -> [u8; 16] {
+    let mut digest = md5::compute(data).0;
+    for _ in 0..50 {
+        digest = md5::compute(&digest).0;
+    }
+    digest
+}
+
+/// Algorithm 3: the `O` entry, the padded user password encrypted with a key from the owner password.
+fn owner_entry(owner_password: &str, user_password: &str) -> Vec<u8> {
+    let owner_key = md5_rounds(&padded(owner_password));
+    rc4_rounds(&owner_key, &padded(user_password))
+}
+
+/// Algorithm 2: the file key, metadata included.
+fn file_key(user_password: &str, owner_entry: &[u8], permissions: i32, first_id: &[u8]) -> [u8; 16] {
+    let mut key_input = padded(user_password);
+    key_input.extend_from_slice(owner_entry);
+    key_input.extend_from_slice(&permissions.to_le_bytes());
+    key_input.extend_from_slice(first_id);
+    md5_rounds(&key_input)
+}
+
+/// Algorithm 5: the `U` entry, padded to 32 bytes.
+fn user_entry(file_key: &[u8; 16], first_id: &[u8]) -> Vec<u8> {
+    let mut user_input = PADDING.to_vec();
+    user_input.extend_from_slice(first_id);
+    let mut user_entry = rc4_rounds(file_key, &md5::compute(&user_input).0);
+    user_entry.resize(32, 0);
+    user_entry
+}
+
+/// Encrypts every string and stream of `doc` with AES for `passwords` and adds the `Encrypt`
+/// dictionary. Must run last, after compression and once the trailer `ID` is set.
+pub fn encrypt_document(
+    doc: &mut lopdf::Document,
+    options: &EncryptionOptions,
+    passwords: &DocumentPasswords,
+) -> Result<(), PromoFinError> {
+    let first_id = match doc.trailer.get(b"ID").and_then(Object::as_array) {
+        Ok(ids) => ids.first().and_then(|id| id.as_str().ok()).map(|id| id.to_vec()).unwrap_or_default(),
+        Err(_) => Vec::new(),
+    };
+    let permissions = options.permissions();
+    let owner_entry = owner_entry(&passwords.owner, &passwords.user);
+    let file_key = file_key(&passwords.user, &owner_entry, permissions, &first_id);
+    let user_entry = user_entry(&file_key, &first_id);
+
+    let ids: Vec<ObjectId> = doc.objects.keys().cloned().collect();
+    for id in ids {
+        let object_key = object_key(&file_key, id);
+        if let Some(object) = doc.objects.get_mut(&id) {
+            encrypt_object(object, &object_key, &passwords.customer)?;
+        }
+    }
+
+    let encrypt_id = doc.add_object(dictionary! {
+        "Filter" => "Standard",
+        "V" => 4,
+        "R" => 4,
+        "Length" => 128,
+        "CF" => dictionary! {
+            "StdCF" => dictionary! { "CFM" => "AESV2", "AuthEvent" => "DocOpen", "Length" => 16 },
+        },
+        "StmF" => "StdCF",
+        "StrF" => "StdCF",
+        "O" => Object::String(owner_entry, StringFormat::Hexadecimal),
+        "U" => Object::String(user_entry, StringFormat::Hexadecimal),
+        "P" => permissions as i64,
+    });
+    doc.trailer.set("Encrypt", encrypt_id);
+    if doc.version.as_str() < "1.6" {
+        doc.version = "1.6".to_owned();
+    }
+    Ok(())
+}
+
+/// Algorithm 1 for AES: the key for one object.
+fn object_key(file_key: &[u8; 16], id: ObjectId) -> [u8; 16] {
+    let mut input = file_key.to_vec();
+    input.extend_from_slice(&id.0.to_le_bytes()[..3]);
+    input.extend_from_slice(&id.1.to_le_bytes()[..2]);
+    input.extend_from_slice(b"sAlT");
+    md5::compute(&input).0
+}
+
+/// AES-128 in CBC mode with PKCS#7 padding, the initialisation vector written first.
+fn aes_encrypt(key: &[u8; 16], iv: [u8; 16], data: &[u8]) -> Vec<u8> {
+    let encrypted = cbc::Encryptor::<aes::Aes128>::new(key.into(), &iv.into()).encrypt_padded_vec_mut::<Pkcs7>(data);
+    let mut out = iv.to_vec();
+    out.extend(encrypted);
+    out
+}
+
+fn encrypt_object(object: &mut Object, key: &[u8; 16], customer: &str) -> Result<(), PromoFinError> {
+    match object {
+        Object::String(bytes, _) => *bytes = aes_encrypt(key, random_bytes(customer)?, bytes),
+        Object::Array(items) => {
+            for item in items.iter_mut() {
+                encrypt_object(item, key, customer)?;
+            }
+        }
+        Object::Dictionary(dict) => {
+            for (_, value) in dict.iter_mut() {
+                encrypt_object(value, key, customer)?;
+            }
+        }
+        Object::Stream(stream) => {
+            for (_, value) in stream.dict.iter_mut() {
+                encrypt_object(value, key, customer)?;
+            }
+            let content = aes_encrypt(key, random_bytes(customer)?, &stream.content);
+            stream.set_content(content);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockDecryptMut;
+    use aes::cipher::generic_array::GenericArray;
+    use backfat::container_objects::text_box::{TextBox, TextAlignment};
+    use backfat::font::font_info::FontInfo;
+    use backfat::font::font_sizes::Font;
+    use crate::config::{LayoutConfig, TextStyle};
+    use crate::metadata::DocumentInfo;
+    use crate::pdf::PdfReportBuilder;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn aes_decrypt(key: &[u8; 16], data: &[u8]) -> Vec<u8> {
+        let (iv, encrypted) = data.split_at(16);
+        cbc::Decryptor::<aes::Aes128>::new(key.into(), GenericArray::from_slice(iv))
+            .decrypt_padded_vec_mut::<Pkcs7>(encrypted)
+            .unwrap()
+    }
+
+    #[test]
+    fn rc4_matches_rfc_6229() {
+        let key_stream = rc4(&[1, 2, 3, 4, 5], &[0; 32]);
+        assert_eq!(hex(&key_stream[..16]), "b2396305f03dc027ccc3524a0a1118a8");
+        assert_eq!(hex(&key_stream[16..]), "6982944f18fc82d589c403a47a0d0919");
+        let key: Vec<u8> = (1..=16).collect();
+        assert_eq!(hex(&rc4(&key, &[0; 16])), "9ac7cc9a609d1ef7b2932899cde41b97");
+    }
+
+    #[test]
+    fn entries_and_keys_match_a_reference_implementation() {
+        // expected values from a separate implementation of algorithms 1 to 5
+        let permissions = EncryptionOptions::default().permissions();
+        assert_eq!(permissions, -1340);
+        let first_id = b"0123456789abcdef";
+        let owner = owner_entry("owner-secret", "acme-123");
+        assert_eq!(hex(&owner), "d8e7240438212d3ec7d2a229d818915e315023748e94c0fdc1e3cbde082e16d4");
+        let key = file_key("acme-123", &owner, permissions, first_id);
+        assert_eq!(hex(&key), "57a7302e6ef074fec1a8c3b0b783dc41");
+        let user = user_entry(&key, first_id);
+        assert_eq!(hex(&user[..16]), "f674cb336637c7d79a7bbfef06e2d467");
+        assert_eq!(user.len(), 32);
+
+        let object_key = object_key(&key, (7, 0));
+        assert_eq!(hex(&object_key), "31627503938cd69f55de2f72148faac4");
+        let mut iv = [0; 16];
+        iv.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        let encrypted = aes_encrypt(&object_key, iv, b"Missing Report");
+        assert_eq!(hex(&encrypted), format!("{}17799cb5e8c9c48fc9acfa0c58047230", hex(&iv)));
+    }
+
+    #[test]
+    fn owner_password_is_random_unless_configured() {
+        let options = EncryptionOptions { password_template: Some("{customer}-7731".to_owned()), ..EncryptionOptions::default() };
+        let (first, second) = (options.passwords("Acme").unwrap(), options.passwords("Acme").unwrap());
+        assert_eq!(first.user, "Acme-7731");
+        assert_eq!(first.owner.len(), 32);
+        assert_ne!(first.owner, second.owner);
+        assert_ne!(first.owner, first.user);
+
+        let options = EncryptionOptions { owner_password: "owner-secret".to_owned(), ..options };
+        assert_eq!(options.passwords("Acme").unwrap().owner, "owner-secret");
+    }
+
+    #[test]
+    fn written_document_decrypts_with_the_user_password() {
+        let mut layout = LayoutConfig::default();
+        layout.encryption = EncryptionOptions {
+            enabled: true,
+            password_template: Some("{customer}-7731".to_owned()),
+            ..EncryptionOptions::default()
+        };
+        let mut builder = PdfReportBuilder::new(&layout.missing_page, &layout).unwrap();
+        builder.describe(DocumentInfo { title: "Acme Missing Report".to_owned(), ..DocumentInfo::default() });
+        builder.protect_for("Acme").unwrap();
+        let mut txt = TextBox::new("For Customer: Acme".to_owned(), FontInfo::new(layout.fonts.title, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
+        builder.text_row(1..99, false, 0.25, TextStyle::Title, &mut txt);
+        let mut pdf = Vec::new();
+        builder.finish(&mut pdf).unwrap();
+
+        let doc = lopdf::Document::load_mem(&pdf).unwrap();
+        assert_eq!(doc.version, "1.6");
+        let encrypt_id = doc.trailer.get(b"Encrypt").unwrap().as_reference().unwrap();
+        let encrypt = doc.get_object(encrypt_id).unwrap().as_dict().unwrap();
+        assert_eq!(encrypt.get(b"V").unwrap().as_i64().unwrap(), 4);
+        let std_cf = encrypt.get(b"CF").unwrap().as_dict().unwrap().get(b"StdCF").unwrap().as_dict().unwrap();
+        assert_eq!(std_cf.get(b"CFM").unwrap().as_name().unwrap(), b"AESV2");
+
+        // authenticate the user password the way a reader does
+        let first_id = doc.trailer.get(b"ID").unwrap().as_array().unwrap()[0].as_str().unwrap();
+        let owner = encrypt.get(b"O").unwrap().as_str().unwrap();
+        let permissions = encrypt.get(b"P").unwrap().as_i64().unwrap() as i32;
+        let key = file_key("Acme-7731", owner, permissions, first_id);
+        assert_eq!(user_entry(&key, first_id)[..16], encrypt.get(b"U").unwrap().as_str().unwrap()[..16]);
+
+        let info_id = doc.trailer.get(b"Info").unwrap().as_reference().unwrap();
+        let info = doc.get_object(info_id).unwrap().as_dict().unwrap();
+        let title = aes_decrypt(&object_key(&key, info_id), info.get(b"Title").unwrap().as_str().unwrap());
+        assert_eq!(title, b"Acme Missing Report");
+
+        let content_id = doc.get_page_contents(doc.get_pages()[&1])[0];
+        let stream = doc.get_object(content_id).unwrap().as_stream().unwrap();
+        let mut plain = lopdf::Stream::new(stream.dict.clone(), aes_decrypt(&object_key(&key, content_id), &stream.content));
+        plain.decompress();
+        assert!(String::from_utf8_lossy(&plain.content).contains("For Customer: Acme"));
+    }
+
+    #[test]
+    fn passwords_are_read_once_per_run() {
+        let path = std::env::temp_dir().join(format!("promo_fin_passwords_{}.csv", std::process::id()));
+        std::fs::write(&path, "Customer,Password\nAcme ,acme-123\n").unwrap();
+        let mut options = EncryptionOptions {
+            enabled: true,
+            passwords_csv: Some(path.to_string_lossy().into_owned()),
+            ..EncryptionOptions::default()
+        };
+        options.load_passwords().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let per_document = options.clone();
+        assert_eq!(per_document.user_password("Acme").unwrap(), "acme-123");
+        assert!(per_document.user_password("Other").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+
+/// Errors raised while loading promo data and writing the reports.
+#[derive(Debug)]
+pub enum PromoFinError {
+    /// The sales input or promo json could not be loaded by `load_promo`.
+    Load {
+        input_file: String,
+        promo_file: String,
+        message: String,
+    },
+    /// An output file could not be created.
+    CreateFile { path: String, source: std::io::Error },
+    /// A run config could not be read or parsed.
+    Config { path: String, message: String },
+    /// A theme logo could not be read or is not a supported PNG or JPEG.
+    Image { path: String, message: String },
+    /// A font face could not be read, parsed or subset for embedding.
+    Font { path: String, message: String },
+    /// Archival output was requested but the document cannot be PDF/A-2b.
+    Archival { problems: Vec<String> },
+    /// A customer's PDF could not be given a password.
+    Encryption { customer: String, message: String },
+    /// A quantity or price cell in the qualifying rows is not a number.
+    InvalidCell {
+        customer: String,
+        section: usize,
+        /// Line of the row in the sales export, when it could be found there.
+        line: Option<usize>,
+        column: &'static str,
+        value: String,
+    },
+    /// lopdf failed to encode a content stream or write the document.
+    Pdf(lopdf::Error),
+    Zip(zip::result::ZipError),
+    Json(serde_json::Error),
+    Csv(csv::Error),
+    Xlsx(rust_xlsxwriter::XlsxError),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for PromoFinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PromoFinError::Load { input_file, promo_file, message } => write!(
+                f,
+                "could not load '{}' with promo '{}': {}",
+                input_file, promo_file, message
+            ),
+            PromoFinError::CreateFile { path, source } => write!(f, "could not create '{}': {}", path, source),
+            PromoFinError::Config { path, message } => write!(f, "invalid config '{}': {}", path, message),
+            PromoFinError::Image { path, message } => write!(f, "invalid logo '{}': {}", path, message),
+            PromoFinError::Font { path, message } => write!(f, "invalid font '{}': {}", path, message),
+            PromoFinError::Archival { problems } => write!(f, "not PDF/A-2b: {}", problems.join("; ")),
+            PromoFinError::Encryption { customer, message } => {
+                write!(f, "could not encrypt the reports of '{}': {}", customer, message)
+            }
+            PromoFinError::InvalidCell { customer, section, line, column, value } => {
+                write!(f, "customer '{}', promo {}", customer, section + 1)?;
+                if let Some(line) = line {
+                    write!(f, ", sales line {}", line)?;
+                }
+                write!(f, ": {} '{}' is not a number", column, value)
+            }
+            PromoFinError::Pdf(e) => write!(f, "pdf error: {}", e),
+            PromoFinError::Zip(e) => write!(f, "zip error: {}", e),
+            PromoFinError::Json(e) => write!(f, "json error: {}", e),
+            PromoFinError::Csv(e) => write!(f, "csv error: {}", e),
+            PromoFinError::Xlsx(e) => write!(f, "xlsx error: {}", e),
+            PromoFinError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PromoFinError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PromoFinError::CreateFile { source, .. } => Some(source),
+            PromoFinError::Pdf(e) => Some(e),
+            PromoFinError::Zip(e) => Some(e),
+            PromoFinError::Json(e) => Some(e),
+            PromoFinError::Csv(e) => Some(e),
+            PromoFinError::Xlsx(e) => Some(e),
+            PromoFinError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<lopdf::Error> for PromoFinError {
+    fn from(e: lopdf::Error) -> Self {
+        PromoFinError::Pdf(e)
+    }
+}
+
+impl From<zip::result::ZipError> for PromoFinError {
+    fn from(e: zip::result::ZipError) -> Self {
+        PromoFinError::Zip(e)
+    }
+}
+
+impl From<serde_json::Error> for PromoFinError {
+    fn from(e: serde_json::Error) -> Self {
+        PromoFinError::Json(e)
+    }
+}
+
+impl From<csv::Error> for PromoFinError {
+    fn from(e: csv::Error) -> Self {
+        PromoFinError::Csv(e)
+    }
+}
+
+impl From<rust_xlsxwriter::XlsxError> for PromoFinError {
+    fn from(e: rust_xlsxwriter::XlsxError) -> Self {
+        PromoFinError::Xlsx(e)
+    }
+}
+
+impl From<std::io::Error> for PromoFinError {
+    fn from(e: std::io::Error) -> Self {
+        PromoFinError::Io(e)
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as FmtWrite;
+use lopdf::content::Operation;
+use lopdf::{dictionary, Object, ObjectId, Stream, StringFormat};
+use ttf_parser::{name_id, Face, GlyphId};
+use crate::config::{FontFaces, TextStyle};
+use crate::error::PromoFinError;
+use crate::pdf::add_resource;
+use crate::text::{decode_win_ansi, encode_win_ansi, helvetica_advance, HELVETICA_RESOURCE};
+
+/// A TrueType or OpenType font embedded as a Type0 font with `Identity-H` encoding.
+///
+/// Text is shown as two byte glyph ids. Every glyph passed through [`EmbeddedFont::encode`] is
+/// recorded so that only those outlines are embedded and the `ToUnicode` map can be written.
+///
+/// backfat measures, wraps and aligns text with Helvetica metrics and offers no way to supply
+/// others, so each glyph is given the Helvetica advance of the character it shows. Text then
+/// takes exactly the room backfat laid out for it, whatever the face's own spacing.
+pub struct EmbeddedFont {
+    path: String,
+    data: Vec<u8>,
+    resource: String,
+    /// Glyph id to the character it was encoded from and that character's Helvetica advance.
+    used: BTreeMap<u16, (char, u16)>,
+}
+
+impl EmbeddedFont {
+    pub fn load(path: &str, resource: String) -> Result<Self, PromoFinError> {
+        let font_err = |message: String| PromoFinError::Font { path: path.to_owned(), message };
+        let data = std::fs::read(path).map_err(|e| font_err(e.to_string()))?;
+        Face::from_slice(&data, 0).map_err(|e| font_err(e.to_string()))?;
+        Ok(Self { path: path.to_owned(), data, resource, used: BTreeMap::new() })
+    }
+
+    fn is_cff(&self) -> bool {
+        self.face().tables().cff.is_some()
+    }
+
+    fn face(&self) -> Face<'_> {
+        // checked in load
+        Face::from_slice(&self.data, 0).unwrap()
+    }
+
+    /// Glyph ids of `text` as big endian pairs, using `.notdef` for characters the font lacks.
+    pub fn encode(&mut self, text: &str) -> Vec<u8> {
+        let face = Face::from_slice(&self.data, 0).unwrap();
+        let mut encoded = Vec::with_capacity(text.len() * 2);
+        for c in text.chars() {
+            let glyph = face.glyph_index(c).unwrap_or(GlyphId(0));
+            let advance = helvetica_advance(encode_win_ansi(c.encode_utf8(&mut [0; 4]))[0]);
+            self.used.entry(glyph.0).or_insert((c, advance));
+            encoded.extend_from_slice(&glyph.0.to_be_bytes());
+        }
+        encoded
+    }
+
+    /// Adds the subset font program, descriptor, CID font and Type0 font, returning the Type0 font.
+    fn add_to_document(&self, doc: &mut lopdf::Document) -> Result<ObjectId, PromoFinError> {
+        let font_err = |message: String| PromoFinError::Font { path: self.path.clone(), message };
+        let face = self.face();
+        let scale = 1000.0 / face.units_per_em() as f64;
+        let is_cff = self.is_cff();
+
+        let mut glyphs: Vec<u16> = self.used.keys().cloned().collect();
+        if !self.used.contains_key(&0) {
+            glyphs.insert(0, 0);
+        }
+        let subset = subsetter::subset(&self.data, 0, subsetter::Profile::pdf(&glyphs))
+            .map_err(|e| font_err(e.to_string()))?;
+
+        let postscript_name = face
+            .names()
+            .into_iter()
+            .find(|name| name.name_id == name_id::POST_SCRIPT_NAME)
+            .and_then(|name| name.to_string())
+            .unwrap_or_else(|| "Embedded".to_owned());
+        let base_font = format!("{}+{}", subset_tag(&glyphs), postscript_name.replace(' ', ""));
+
+        let font_file_id = if is_cff {
+            doc.add_object(Stream::new(dictionary! { "Subtype" => "OpenType" }, subset))
+        } else {
+            let length = subset.len() as i64;
+            doc.add_object(Stream::new(dictionary! { "Length1" => length }, subset))
+        };
+
+        let bbox = face.global_bounding_box();
+        let mut flags = 32;
+        if face.is_monospaced() {
+            flags |= 1;
+        }
+        if face.is_italic() {
+            flags |= 64;
+        }
+        let scaled = |value: i16| (value as f64 * scale).round() as i64;
+        let mut descriptor = dictionary! {
+            "Type" => "FontDescriptor",
+            "FontName" => Object::Name(base_font.as_bytes().to_vec()),
+            "Flags" => flags,
+            "FontBBox" => vec![
+                scaled(bbox.x_min).into(), scaled(bbox.y_min).into(),
+                scaled(bbox.x_max).into(), scaled(bbox.y_max).into(),
+            ],
+            "ItalicAngle" => face.italic_angle().unwrap_or(0.0) as f64,
+            "Ascent" => scaled(face.ascender()),
+            "Descent" => scaled(face.descender()),
+            "CapHeight" => scaled(face.capital_height().unwrap_or(face.ascender())),
+            "StemV" => 80,
+        };
+        descriptor.set(if is_cff { "FontFile3" } else { "FontFile2" }, font_file_id);
+        let descriptor_id = doc.add_object(descriptor);
+
+        let mut widths: Vec<Object> = Vec::new();
+        for (glyph, (_, advance)) in &self.used {
+            widths.push((*glyph as i64).into());
+            widths.push(vec![(*advance as i64).into()].into());
+        }
+        let mut cid_font = dictionary! {
+            "Type" => "Font",
+            "Subtype" => if is_cff { "CIDFontType0" } else { "CIDFontType2" },
+            "BaseFont" => Object::Name(base_font.as_bytes().to_vec()),
+            "CIDSystemInfo" => dictionary! {
+                "Registry" => Object::string_literal("Adobe"),
+                "Ordering" => Object::string_literal("Identity"),
+                "Supplement" => 0,
+            },
+            "FontDescriptor" => descriptor_id,
+            "W" => widths,
+        };
+        if !is_cff {
+            cid_font.set("CIDToGIDMap", "Identity");
+        }
+        let cid_font_id = doc.add_object(cid_font);
+
+        let to_unicode_id = doc.add_object(Stream::new(dictionary! {}, self.to_unicode_cmap().into_bytes()));
+        Ok(doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type0",
+            "BaseFont" => Object::Name(base_font.as_bytes().to_vec()),
+            "Encoding" => "Identity-H",
+            "DescendantFonts" => vec![cid_font_id.into()],
+            "ToUnicode" => to_unicode_id,
+        }))
+    }
+
+    /// CMap from the used glyph ids back to Unicode so text can be searched and copied.
+    fn to_unicode_cmap(&self) -> String {
+        let mut cmap = String::from(
+            "/CIDInit /ProcSet findresource begin\n12 dict begin\nbegincmap\n\
+             /CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def\n\
+             /CMapName /Adobe-Identity-UCS def\n/CMapType 2 def\n\
+             1 begincodespacerange\n<0000> <FFFF>\nendcodespacerange\n",
+        );
+        let mapped: Vec<(&u16, &char)> = self.used.iter().filter(|g| *g.0 != 0).map(|g| (g.0, &(g.1).0)).collect();
+        // bfchar blocks hold at most 100 entries
+        for block in mapped.chunks(100) {
+            let _ = writeln!(cmap, "{} beginbfchar", block.len());
+            for (glyph, c) in block {
+                let mut units = [0u16; 2];
+                let hex: String = c.encode_utf16(&mut units).iter().map(|u| format!("{:04X}", u)).collect();
+                let _ = writeln!(cmap, "<{:04X}> <{}>", glyph, hex);
+            }
+            cmap.push_str("endbfchar\n");
+        }
+        cmap.push_str("endcmap\nCMapName currentdict /CMap defineresource pop\nend\nend\n");
+        cmap
+    }
+}
+
+/// Six capital letters derived from the glyph set, prefixed to subset font names.
+fn subset_tag(glyphs: &[u16]) -> String {
+    let mut hash: u32 = 2166136261;
+    for glyph in glyphs {
+        for byte in glyph.to_be_bytes().iter() {
+            hash = (hash ^ *byte as u32).wrapping_mul(16777619);
+        }
+    }
+    (0..6)
+        .map(|_| {
+            let letter = (b'A' + (hash % 26) as u8) as char;
+            hash = hash / 26;
+            letter
+        })
+        .collect()
+}
+
+/// The embedded fonts of a document and the text style each one replaces Helvetica for.
+///
+/// The report builder draws each container in a [`TextStyle`], and its `Tf` operations are
+/// switched to that style's face as they are drawn, using the names from
+/// [`style_resources`](Self::style_resources). Running headers, footers, the brand title and
+/// any other text left in Helvetica use the default face.
+pub struct FontSet {
+    fonts: Vec<EmbeddedFont>,
+    styles: HashMap<TextStyle, usize>,
+    default: Option<usize>,
+}
+
+impl FontSet {
+    pub fn load(faces: &FontFaces) -> Result<Self, PromoFinError> {
+        let mut set = FontSet { fonts: Vec::new(), styles: HashMap::new(), default: None };
+        set.default = set.font_index(&faces.default)?;
+        for style in TextStyle::ALL.iter() {
+            if let Some(index) = set.font_index(faces.face(*style))?.or(set.default) {
+                set.styles.insert(*style, index);
+            }
+        }
+        Ok(set)
+    }
+
+    /// Loads `path` unless an earlier style already uses the same file.
+    fn font_index(&mut self, path: &Option<String>) -> Result<Option<usize>, PromoFinError> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        if let Some(index) = self.fonts.iter().position(|f| &f.path == path) {
+            return Ok(Some(index));
+        }
+        let resource = format!("PFEmb{}", self.fonts.len());
+        self.fonts.push(EmbeddedFont::load(path, resource)?);
+        Ok(Some(self.fonts.len() - 1))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fonts.is_empty()
+    }
+
+    /// Whether a default face replaces [`HELVETICA_RESOURCE`].
+    pub fn has_default(&self) -> bool {
+        self.default.is_some()
+    }
+
+    /// Whether a CFF based OpenType face shows any glyph, which needs PDF 1.6 for `FontFile3`.
+    pub fn needs_pdf_1_6(&self) -> bool {
+        self.fonts.iter().any(|f| !f.used.is_empty() && f.is_cff())
+    }
+
+    /// Resource name of the face each style is shown in, for the styles that have one.
+    pub fn style_resources(&self) -> HashMap<TextStyle, Vec<u8>> {
+        self.styles
+            .iter()
+            .map(|(style, index)| (*style, self.fonts[*index].resource.as_bytes().to_vec()))
+            .collect()
+    }
+
+    /// Re-encodes the strings shown with an embedded face as glyph ids, first switching the
+    /// Helvetica `Tf` operations still left to the default face.
+    ///
+    /// Strings are read as UTF-8, falling back to WinAnsi for bytes that are not valid UTF-8.
+    pub fn embed_operations(&mut self, operations: &mut Vec<Operation>, helvetica_names: &HashSet<Vec<u8>>) {
+        let mut current: Option<usize> = None;
+        for operation in operations.iter_mut() {
+            match operation.operator.as_str() {
+                "Tf" if operation.operands.len() == 2 => {
+                    current = match &operation.operands[0] {
+                        Object::Name(name) if helvetica_names.contains(name) => self.default,
+                        Object::Name(name) => self.fonts.iter().position(|f| f.resource.as_bytes() == name.as_slice()),
+                        _ => None,
+                    };
+                    if let Some(index) = current {
+                        operation.operands[0] = Object::Name(self.fonts[index].resource.as_bytes().to_vec());
+                    }
+                }
+                "Tj" | "'" | "\"" | "TJ" => {
+                    if let Some(index) = current {
+                        let font = &mut self.fonts[index];
+                        for operand in operation.operands.iter_mut() {
+                            reencode(font, operand);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Adds every font that shows at least one glyph to the document and its resources.
+    pub fn add_to_document(&self, doc: &mut lopdf::Document, resources_id: ObjectId) -> Result<(), PromoFinError> {
+        for font in self.fonts.iter().filter(|f| !f.used.is_empty()) {
+            let font_id = font.add_to_document(doc)?;
+            add_resource(doc, resources_id, b"Font", &font.resource, font_id)?;
+        }
+        Ok(())
+    }
+}
+
+fn reencode(font: &mut EmbeddedFont, operand: &mut Object) {
+    match operand {
+        Object::String(bytes, _) => {
+            let text = match String::from_utf8(bytes.clone()) {
+                Ok(text) => text,
+                Err(_) => decode_win_ansi(bytes),
+            };
+            *operand = Object::String(font.encode(&text), StringFormat::Hexadecimal);
+        }
+        Object::Array(items) => {
+            for item in items.iter_mut() {
+                reencode(font, item);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Drops `names` from the `Font` resources along with the font objects they point to.
+///
+/// Used once every Helvetica operation has been moved to an embedded face, so that no
+/// unembedded font is left in the file.
+pub fn remove_font_resources(doc: &mut lopdf::Document, resources_id: ObjectId, names: &HashSet<Vec<u8>>) -> Result<(), PromoFinError> {
+    let fonts_ref = match doc.get_object(resources_id)?.as_dict()?.get(b"Font") {
+        Ok(Object::Reference(id)) => Some(*id),
+        Ok(_) => None,
+        Err(_) => return Ok(()),
+    };
+    let fonts = match fonts_ref {
+        Some(id) => doc.get_object_mut(id)?.as_dict_mut()?,
+        None => doc.get_object_mut(resources_id)?.as_dict_mut()?.get_mut(b"Font")?.as_dict_mut()?,
+    };
+    let mut removed = Vec::new();
+    for name in names {
+        if let Some(Object::Reference(id)) = fonts.remove(name) {
+            removed.push(id);
+        }
+    }
+    for id in removed {
+        doc.objects.remove(&id);
+    }
+    Ok(())
+}
+
+/// Names in the `Font` resources that refer to Helvetica, plus the one used for page decorations.
+pub fn helvetica_resource_names(doc: &lopdf::Document, resources_id: ObjectId) -> Result<HashSet<Vec<u8>>, PromoFinError> {
+    let mut names = HashSet::new();
+    names.insert(HELVETICA_RESOURCE.as_bytes().to_vec());
+    let fonts = match doc.get_object(resources_id)?.as_dict()?.get(b"Font") {
+        Ok(fonts) => doc.dereference(fonts)?.1.as_dict()?,
+        Err(_) => return Ok(names),
+    };
+    for (name, font) in fonts.iter() {
+        let font = match doc.dereference(font)?.1.as_dict() {
+            Ok(font) => font,
+            Err(_) => continue,
+        };
+        if let Ok(Object::Name(base_font)) = font.get(b"BaseFont") {
+            if base_font.starts_with(b"Helvetica") {
+                names.insert(name.clone());
+            }
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FACE: &str = "tests/fixtures/DejaVuSansMono-Oblique.ttf";
+
+    fn tf(name: &str, size: f64) -> Operation {
+        Operation::new("Tf", vec![Object::Name(name.as_bytes().to_vec()), size.into()])
+    }
+
+    fn tj(text: &str) -> Operation {
+        Operation::new("Tj", vec![Object::string_literal(text)])
+    }
+
+    #[test]
+    fn decoration_text_uses_the_default_face() {
+        let faces = FontFaces { default: Some(FACE.to_owned()), ..FontFaces::default() };
+        let mut set = FontSet::load(&faces).unwrap();
+        let mut operations = vec![tf(HELVETICA_RESOURCE, 14.0), tf("F1", 14.0)];
+        let helvetica_names: HashSet<Vec<u8>> = vec![HELVETICA_RESOURCE.as_bytes().to_vec()].into_iter().collect();
+        set.embed_operations(&mut operations, &helvetica_names);
+        assert_eq!(operations[0].operands[0].as_name().unwrap(), b"PFEmb0");
+        assert_eq!(operations[1].operands[0].as_name().unwrap(), b"F1");
+    }
+
+    #[test]
+    fn styles_of_one_size_keep_their_own_faces() {
+        // a second path to the same file is loaded as a face of its own
+        let other = "tests/fixtures/../fixtures/DejaVuSansMono-Oblique.ttf".to_owned();
+        let faces = FontFaces { title: Some(FACE.to_owned()), heading: Some(other), ..FontFaces::default() };
+        let mut set = FontSet::load(&faces).unwrap();
+        let resources = set.style_resources();
+        assert_eq!(resources[&TextStyle::Title], b"PFEmb0".to_vec());
+        assert_eq!(resources[&TextStyle::Heading], b"PFEmb1".to_vec());
+        assert!(!resources.contains_key(&TextStyle::Body));
+
+        let mut operations = vec![tf("PFEmb1", 16.0), tj("Ai"), tf("F1", 16.0), tj("Ai")];
+        set.embed_operations(&mut operations, &HashSet::new());
+        assert!(set.fonts[0].used.is_empty());
+        // glyphs take Helvetica's advances, which backfat laid the text out with
+        let advances: Vec<(char, u16)> = set.fonts[1].used.values().cloned().collect();
+        assert!(advances.contains(&('A', 667)) && advances.contains(&('i', 222)));
+        assert_eq!(operations[3].operands[0].as_str().unwrap(), b"Ai");
+    }
+}
@@ -0,0 +1,236 @@
+use std::fmt::Write as FmtWrite;
+use promo_input::general::and_or::AndOrType;
+use crate::archive::unique_customer_names;
+use crate::error::PromoFinError;
+use crate::missing_report::{generate_missing_report_for_section, NeededSections, PromoRun};
+use crate::output::ReportSink;
+use crate::recommend::{cheapest_path, describe_recommendation};
+
+/// Part numbers per row in the missing report grid, matching the PDF.
+const GRID_COLUMNS: usize = 5;
+
+const DETAIL_HEADER: [&str; 7] = [
+    "Ship Date",
+    "Customer Name",
+    "Order Number",
+    "Qty",
+    "Part Number",
+    "Part Number Description",
+    "Sale Price",
+];
+
+const STYLE: &str = "
+body { font-family: Helvetica, Arial, sans-serif; margin: 2em; color: #222; }
+h1 { font-size: 1.6em; }
+h2 { font-size: 1.3em; margin-top: 1.5em; }
+table { border-collapse: collapse; margin: 0.5em 0 1em 0; }
+td, th { border: 1px solid #444; padding: 0.2em 0.6em; }
+th { background: #eee; text-align: left; }
+td.num { text-align: right; }
+.grid td { text-align: center; min-width: 7em; }
+.purchase td { color: #4d4de6; font-weight: bold; text-align: center; }
+.and { text-align: center; font-weight: bold; margin: 0.3em 0; }
+.total { font-weight: bold; }
+.recommended { font-style: italic; }
+ul.customers { columns: 3; }
+";
+
+/// Writes a self-contained static site: `index.html` linking to one page per customer
+/// under `customers/`, each with the missing report and the qualifying detail tables.
+pub fn write_html_site(promo_run: &PromoRun, sink: &mut dyn ReportSink) -> Result<(), PromoFinError> {
+    let file_names = unique_customer_names(promo_run.data.keys());
+    let mut customers: Vec<&String> = promo_run.data.keys().collect();
+    customers.sort();
+
+    let mut index = String::new();
+    write_head(&mut index, "Promotion Reports", "");
+    index.push_str("<h1>Promotion Reports</h1>\n<ul class=\"customers\">\n");
+    for customer in &customers {
+        let _ = writeln!(
+            index,
+            "<li><a href=\"customers/{}.html\">{}</a></li>",
+            escape_url(&file_names[*customer]),
+            escape(customer)
+        );
+    }
+    index.push_str("</ul>\n</body>\n</html>\n");
+    sink.write_entry("index.html", index.as_bytes())?;
+
+    for customer in customers {
+        let page = render_customer_page(promo_run, customer);
+        sink.write_entry(&format!("customers/{}.html", file_names[customer]), page.as_bytes())?;
+    }
+    sink.finish()
+}
+
+fn write_head(out: &mut String, title: &str, root: &str) {
+    let _ = write!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n",
+        escape(title),
+        STYLE
+    );
+    if !root.is_empty() {
+        let _ = writeln!(out, "<p><a href=\"{}index.html\">All customers</a></p>", root);
+    }
+}
+
+fn render_customer_page(promo_run: &PromoRun, customer: &str) -> String {
+    let mut out = String::new();
+    write_head(&mut out, customer, "../");
+    let _ = writeln!(out, "<h1>For Customer: {}</h1>", escape(customer));
+
+    let promo = &promo_run.data[customer];
+    for (sec_id, section) in promo.promo_sections.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "<h2>Qualified {} times for Promo {}</h2>",
+            section.times_section_qualified,
+            sec_id + 1
+        );
+        if promo_run.caps.reached(sec_id, section.times_section_qualified) {
+            out.push_str("<p>Maximum reached</p>\n");
+        } else {
+            if section.times_section_qualified == 0 {
+                out.push_str("<p>To get the promo</p>\n");
+            } else {
+                out.push_str("<p>To get another promo</p>\n");
+            }
+            let needed = generate_missing_report_for_section(section);
+            render_missing_section(&mut out, &needed);
+            if let Some(recommendation) = cheapest_path(customer, &needed, &promo_run.prices) {
+                let _ = writeln!(out, "<p class=\"recommended\">{}</p>", escape(&describe_recommendation(&recommendation)));
+            }
+        }
+
+        if section.times_section_qualified > 0 {
+            render_detail_section(&mut out, &promo_run.detail_rows(section));
+        }
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// Same "Purchase N more" rows and part-number grid as `write_missing_report_to_pdf_new`.
+fn render_missing_section(out: &mut String, missing_report: &[NeededSections]) {
+    for (sec_index, sec) in missing_report.iter().enumerate() {
+        for (join_type, items) in &sec.missing_part_numbers {
+            out.push_str("<table class=\"grid\">\n");
+            for (item_idx, item) in items.iter().enumerate() {
+                let purchase = if item_idx == 0 {
+                    format!("Purchase {} more", item.amount_needed)
+                } else {
+                    match join_type {
+                        AndOrType::Any(_) => format!("Or purchase {} more", item.amount_needed),
+                        _ => format!("{} purchase {} more", join_type, item.amount_needed),
+                    }
+                };
+                let _ = writeln!(
+                    out,
+                    "<tr class=\"purchase\"><td colspan=\"{}\">{}</td></tr>",
+                    GRID_COLUMNS,
+                    escape(&purchase)
+                );
+                for row in item.missing_part_numbers.chunks(GRID_COLUMNS) {
+                    out.push_str("<tr>");
+                    for part_number in row {
+                        let _ = write!(out, "<td>{}</td>", escape(part_number));
+                    }
+                    out.push_str("</tr>\n");
+                }
+            }
+            out.push_str("</table>\n");
+        }
+        if sec_index < missing_report.len() - 1 {
+            out.push_str("<p class=\"and\">And</p>\n");
+        }
+    }
+}
+
+fn render_detail_section(out: &mut String, data: &[Vec<Vec<String>>]) {
+    out.push_str("<h3>Qualifying purchases</h3>\n");
+    for group in data.iter().filter(|g| !g.is_empty()) {
+        out.push_str("<table class=\"detail\">\n<tr>");
+        for title in DETAIL_HEADER.iter() {
+            let _ = write!(out, "<th>{}</th>", title);
+        }
+        out.push_str("</tr>\n");
+        let mut total_qty = 0.0;
+        for row in group {
+            total_qty += row[3].trim().parse::<f64>().unwrap_or(0.0);
+            out.push_str("<tr>");
+            for (col, cell) in row.iter().enumerate() {
+                let class = if col == 3 || col == 6 { " class=\"num\"" } else { "" };
+                let _ = write!(out, "<td{}>{}</td>", class, escape(cell));
+            }
+            out.push_str("</tr>\n");
+        }
+        let _ = writeln!(
+            out,
+            "<tr class=\"total\"><td colspan=\"3\">Total Quantity</td><td class=\"num\">{}</td><td colspan=\"3\"></td></tr>",
+            total_qty
+        );
+        out.push_str("</table>\n");
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Percent-encodes a file name for use in an href.
+fn escape_url(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            b => {
+                let _ = write!(out, "%{:02X}", b);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::MemorySink;
+    use crate::test_fixtures::{promo_run, promotion, sale, section};
+
+    #[test]
+    fn index_links_every_customer_page_and_escapes_names() {
+        let odd_name = "Bolt <&\">";
+        let run = promo_run(vec![
+            ("Acme", promotion(vec![section(1, 5, 6, "AB-100", vec![sale("Acme", "6", "AB-100", "2.50")])])),
+            (odd_name, promotion(vec![section(0, 5, 1, "AB-100", vec![sale(odd_name, "1", "AB-100", "2.50")])])),
+        ]);
+        let mut memory = MemorySink::new();
+        write_html_site(&run, &mut memory).unwrap();
+
+        let paths: Vec<String> = memory.entries().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, vec!["index.html", "customers/Acme.html", "customers/Bolt _&__.html"]);
+        let index = String::from_utf8(memory.get("index.html").unwrap()).unwrap();
+        assert!(index.contains("<a href=\"customers/Acme.html\">Acme</a>"));
+        assert!(index.contains("<a href=\"customers/Bolt%20_%26__.html\">Bolt &lt;&amp;&quot;&gt;</a>"));
+        assert!(!index.contains(odd_name));
+
+        let page = String::from_utf8(memory.get("customers/Bolt _&__.html").unwrap()).unwrap();
+        assert!(page.contains("<title>Bolt &lt;&amp;&quot;&gt;</title>"));
+        assert!(page.contains("<h1>For Customer: Bolt &lt;&amp;&quot;&gt;</h1>"));
+        // not qualified yet, so there is no detail table that could show the raw name
+        assert!(!page.contains(odd_name));
+        assert!(!page.contains("Qualifying purchases"));
+    }
+}
@@ -1,2 +1,21 @@
+pub mod archive;
+pub mod config;
+pub mod encryption;
+pub mod error;
+pub mod fonts;
+pub mod html;
+pub mod metadata;
 pub mod missing_report;
+pub mod near_miss;
+pub mod output;
+pub mod payout;
 pub mod pdf;
+pub mod pdfa;
+pub mod recommend;
+pub mod text;
+pub mod theme;
+pub mod tiers;
+pub mod xlsx;
+
+#[cfg(test)]
+mod test_fixtures;
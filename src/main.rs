@@ -1,16 +1,202 @@
-mod missing_report;
-mod pdf;
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process;
+use structopt::StructOpt;
+use promo_fin::missing_report;
+use promo_fin::archive::ArchiveLayout;
+use promo_fin::config::{LayoutConfig, ReportSelection, RunConfig};
+use promo_fin::error::PromoFinError;
+use promo_fin::output::{DirectorySink, ReportSink, ZipSink};
+use promo_fin::xlsx::{write_detail_workbook, SheetPer};
+use promo_fin::html::write_html_site;
 
+/// Exit code when a run fails while loading data or writing reports.
+const EXIT_RUN_FAILED: i32 = 1;
+/// Exit code when an input file given on the command line does not exist.
+const EXIT_BAD_INPUT: i32 = 2;
 
-fn main() {
-    //let file = r#"F:\3M Promo Data\May1-July31 2020\ViewExport Customer Detail.xlsx"#;
-    let file = r##"F:\3M Promo Data\May1-July31 2020\data.csv"##;
-    let json = r##"F:\3M Promo Data\May1-July31 2020\promo_May1_2020-July31_2020.json"##;
+#[derive(StructOpt, Debug)]
+#[structopt(name = "promo_fin", about = "Generates promotion missing and qualifying detail reports.")]
+enum Command {
+    /// Write the combined missing report for all customers to a single PDF.
+    Missing {
+        #[structopt(flatten)]
+        inputs: Inputs,
+        #[structopt(flatten)]
+        combined: Combined,
+    },
+    /// Write the per-customer missing reports and qualifying detail PDFs to a zip or folder.
+    Detail {
+        #[structopt(flatten)]
+        inputs: Inputs,
+        #[structopt(flatten)]
+        destination: Destination,
+    },
+    /// Write both the combined missing report PDF and the per-customer zip or folder.
+    All {
+        #[structopt(flatten)]
+        inputs: Inputs,
+        #[structopt(flatten)]
+        combined: Combined,
+        #[structopt(flatten)]
+        destination: Destination,
+    },
+    /// Run the reports described by a toml or json run config.
+    Run {
+        /// Path of the run config.
+        #[structopt(parse(from_os_str))]
+        config: PathBuf,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+struct Combined {
+    /// Path of the combined missing report PDF.
+    #[structopt(short = "p", long = "pdf", parse(from_os_str), default_value = "Missing Report.pdf")]
+    pdf: PathBuf,
+    /// Also write the missing report model as json to this path.
+    #[structopt(short = "j", long = "json", parse(from_os_str))]
+    json: Option<PathBuf>,
+    /// Also write the missing report as csv to this path.
+    #[structopt(short = "c", long = "csv", parse(from_os_str))]
+    csv: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+struct Destination {
+    /// Path of the zip archive holding the per-customer PDFs.
+    #[structopt(short = "z", long = "zip", parse(from_os_str), default_value = "promo.zip")]
+    zip: PathBuf,
+    /// Write the per-customer PDFs into this folder instead of a zip.
+    #[structopt(short = "d", long = "dir", parse(from_os_str))]
+    dir: Option<PathBuf>,
+    /// Also write the qualifying detail as an xlsx workbook to this path.
+    #[structopt(short = "x", long = "xlsx", parse(from_os_str))]
+    xlsx: Option<PathBuf>,
+    /// Put each promo section on its own worksheet instead of one sheet per customer.
+    #[structopt(long = "sheet-per-section")]
+    sheet_per_section: bool,
+    /// Also write a static html site of the reports into this folder.
+    #[structopt(long = "html", parse(from_os_str))]
+    html: Option<PathBuf>,
+}
+
+impl Destination {
+    fn sink(&self) -> Result<Box<dyn ReportSink>, PromoFinError> {
+        match &self.dir {
+            Some(dir) => Ok(Box::new(DirectorySink::new(dir))),
+            None => Ok(Box::new(ZipSink::create(&self.zip)?)),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct Inputs {
+    /// Sales export (csv) to evaluate against the promotion.
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    /// Promotion definition json.
+    #[structopt(parse(from_os_str))]
+    promo: PathBuf,
+    /// Csv of Part Number and Price preferred over past sale prices for recommendations.
+    #[structopt(long = "price-list", parse(from_os_str))]
+    price_list: Option<PathBuf>,
+}
+
+fn path_str(path: &Path) -> Result<&str, String> {
+    path.to_str().ok_or_else(|| format!("path '{}' is not valid UTF-8", path.display()))
+}
+
+fn check_inputs(inputs: &Inputs) -> Result<(), String> {
+    let mut paths = vec![&inputs.input, &inputs.promo];
+    paths.extend(&inputs.price_list);
+    for path in paths {
+        if !path.is_file() {
+            return Err(format!("input file '{}' does not exist", path.display()));
+        }
+    }
+    Ok(())
+}
+
+/// Parses the run config at `config` and checks the input files it names.
+fn check_config(config: &Path) -> Result<RunConfig, String> {
+    let config = RunConfig::from_file(config).map_err(|e| e.to_string())?;
+    check_inputs(&Inputs {
+        input: PathBuf::from(&config.input_file),
+        promo: PathBuf::from(&config.promo_file),
+        price_list: config.price_list.as_ref().map(PathBuf::from),
+    })?;
+    Ok(config)
+}
+
+fn create_file(path: &Path) -> Result<File, String> {
+    File::create(path).map_err(|e| format!("could not create '{}': {}", path.display(), e))
+}
 
-    let mut file_r = File::create("Missing Report.pdf");
-    if let Ok(ref mut fl) = file_r {
-        missing_report::run_missing_reports(file, json, Some(fl), r#"F:\rustprojects\promo_fin\promo.zip"#).expect("Fail");
+/// Writes the reports asked for on the command line.
+fn run(inputs: &Inputs, combined: Option<&Combined>, destination: Option<&Destination>) -> Result<(), Box<dyn std::error::Error>> {
+    let layout = LayoutConfig::default();
+    let mut promo_run = missing_report::load_promo_run(path_str(&inputs.input)?, path_str(&inputs.promo)?)?;
+    if let Some(price_list) = &inputs.price_list {
+        promo_run.prices.load_price_list(path_str(price_list)?)?;
     }
 
+    if let Some(combined) = combined {
+        missing_report::write_missing_report_to_pdf(&promo_run.data, &promo_run.prices, &promo_run.caps, &layout, &mut create_file(&combined.pdf)?)?;
+        if let Some(json) = &combined.json {
+            missing_report::write_missing_report_json(&promo_run.data, &promo_run.prices, &promo_run.caps, &mut create_file(json)?)?;
+        }
+        if let Some(csv) = &combined.csv {
+            missing_report::write_missing_report_csv(&promo_run.data, &promo_run.prices, &promo_run.caps, &mut create_file(csv)?)?;
+        }
+    }
+    if let Some(destination) = destination {
+        if let Some(xlsx) = &destination.xlsx {
+            let sheet_per = if destination.sheet_per_section { SheetPer::Section } else { SheetPer::Customer };
+            write_detail_workbook(&promo_run, sheet_per, create_file(xlsx)?)?;
+        }
+        if let Some(html) = &destination.html {
+            write_html_site(&promo_run, &mut DirectorySink::new(html))?;
+        }
+        let mut sinks = vec![destination.sink()?];
+        missing_report::write_customer_reports(
+            &promo_run,
+            &mut sinks,
+            &ArchiveLayout::default(),
+            &layout,
+            &ReportSelection::default(),
+        )?;
+    }
+    Ok(())
+}
+
+fn exit_on_bad_input<T>(checked: Result<T, String>) -> T {
+    checked.unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        process::exit(EXIT_BAD_INPUT);
+    })
+}
+
+fn main() {
+    let command = Command::from_args();
+
+    let (inputs, combined, destination) = match &command {
+        Command::Missing { inputs, combined } => (inputs, Some(combined), None),
+        Command::Detail { inputs, destination } => (inputs, None, Some(destination)),
+        Command::All { inputs, combined, destination } => (inputs, Some(combined), Some(destination)),
+        Command::Run { config } => {
+            let config = exit_on_bad_input(check_config(config));
+            if let Err(e) = missing_report::run_with_config(&config) {
+                eprintln!("error: {}", e);
+                process::exit(EXIT_RUN_FAILED);
+            }
+            return;
+        }
+    };
+    exit_on_bad_input(check_inputs(inputs));
+
+    if let Err(e) = run(inputs, combined, destination) {
+        eprintln!("error: {}", e);
+        process::exit(EXIT_RUN_FAILED);
+    }
 }
@@ -0,0 +1,191 @@
+use std::fmt::Write as FmtWrite;
+use std::time::{SystemTime, UNIX_EPOCH};
+use lopdf::{dictionary, Dictionary, Stream};
+use serde::{Deserialize, Serialize};
+use crate::text::pdf_text_string;
+
+/// Document information shared by every generated PDF.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct DocumentMetadata {
+    pub author: String,
+    pub creator: String,
+    /// Added after the customer, section and period keywords of each document.
+    pub keywords: Vec<String>,
+    /// Also writes the information as an XMP metadata stream for archival systems.
+    pub xmp: bool,
+}
+
+impl Default for DocumentMetadata {
+    fn default() -> Self {
+        Self { author: String::new(), creator: "promo_fin".to_owned(), keywords: Vec::new(), xmp: false }
+    }
+}
+
+const PRODUCER: &str = "lopdf";
+
+/// What one document is about, set by the report that draws it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DocumentInfo {
+    pub title: String,
+    pub subject: String,
+    pub keywords: Vec<String>,
+}
+
+/// A UTC timestamp broken into calendar fields.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timestamp {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+        Self::from_unix(seconds)
+    }
+
+    pub fn from_unix(seconds: i64) -> Self {
+        let days = seconds.div_euclid(86400);
+        let of_day = seconds.rem_euclid(86400);
+        // civil_from_days: days since 1970-01-01 to a proleptic Gregorian date
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        Self {
+            year,
+            month,
+            day,
+            hour: (of_day / 3600) as u32,
+            minute: (of_day % 3600 / 60) as u32,
+            second: (of_day % 60) as u32,
+        }
+    }
+
+    /// `D:YYYYMMDDHHmmSSZ` as used in the information dictionary.
+    pub fn pdf_date(&self) -> String {
+        format!(
+            "D:{:04}{:02}{:02}{:02}{:02}{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+
+    /// ISO 8601 as used in XMP.
+    pub fn xmp_date(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// File identifier for the trailer `ID`, derived from what the document is and when it was made.
+pub fn document_id(info: &DocumentInfo, created: &Timestamp) -> Vec<u8> {
+    let seed = format!("{}|{}|{}", info.title, info.subject, created.pdf_date());
+    let mut id = Vec::with_capacity(16);
+    for basis in [0xcbf29ce484222325u64, 0x84222325cbf29ce4u64].iter() {
+        let mut hash = *basis;
+        for byte in seed.bytes() {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+        id.extend_from_slice(&hash.to_be_bytes());
+    }
+    id
+}
+
+fn all_keywords(metadata: &DocumentMetadata, info: &DocumentInfo) -> String {
+    info.keywords
+        .iter()
+        .chain(metadata.keywords.iter())
+        .filter(|k| !k.is_empty())
+        .cloned()
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// The document information dictionary, leaving out empty entries.
+pub fn info_dictionary(metadata: &DocumentMetadata, info: &DocumentInfo, created: &Timestamp) -> Dictionary {
+    let mut dict = dictionary! {
+        "Producer" => pdf_text_string(PRODUCER),
+        "CreationDate" => pdf_text_string(&created.pdf_date()),
+    };
+    let keywords = all_keywords(metadata, info);
+    let entries = [
+        ("Title", &info.title),
+        ("Author", &metadata.author),
+        ("Subject", &info.subject),
+        ("Keywords", &keywords),
+        ("Creator", &metadata.creator),
+    ];
+    for (key, value) in entries.iter() {
+        if !value.is_empty() {
+            dict.set(*key, pdf_text_string(value));
+        }
+    }
+    dict
+}
+
+/// The same information as an XMP packet in a `Metadata` stream.
+///
+/// `extra_schemas` are `rdf:Description` elements added beside the standard ones, such as
+/// the PDF/A identification.
+pub fn xmp_stream(metadata: &DocumentMetadata, info: &DocumentInfo, created: &Timestamp, extra_schemas: &str) -> Stream {
+    let mut xmp = String::new();
+    xmp.push_str("<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n");
+    xmp.push_str("<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n");
+    xmp.push_str("<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n");
+
+    xmp.push_str("<rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n");
+    xmp.push_str("<dc:format>application/pdf</dc:format>\n");
+    if !info.title.is_empty() {
+        let _ = writeln!(xmp, "<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>", escape_xml(&info.title));
+    }
+    if !metadata.author.is_empty() {
+        let _ = writeln!(xmp, "<dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>", escape_xml(&metadata.author));
+    }
+    if !info.subject.is_empty() {
+        let _ = writeln!(xmp, "<dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description>", escape_xml(&info.subject));
+    }
+    xmp.push_str("</rdf:Description>\n");
+
+    xmp.push_str("<rdf:Description rdf:about=\"\" xmlns:pdf=\"http://ns.adobe.com/pdf/1.3/\">\n");
+    let _ = writeln!(xmp, "<pdf:Producer>{}</pdf:Producer>", PRODUCER);
+    let keywords = all_keywords(metadata, info);
+    if !keywords.is_empty() {
+        let _ = writeln!(xmp, "<pdf:Keywords>{}</pdf:Keywords>", escape_xml(&keywords));
+    }
+    xmp.push_str("</rdf:Description>\n");
+
+    xmp.push_str("<rdf:Description rdf:about=\"\" xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\">\n");
+    let _ = writeln!(xmp, "<xmp:CreateDate>{}</xmp:CreateDate>", created.xmp_date());
+    if !metadata.creator.is_empty() {
+        let _ = writeln!(xmp, "<xmp:CreatorTool>{}</xmp:CreatorTool>", escape_xml(&metadata.creator));
+    }
+    xmp.push_str("</rdf:Description>\n");
+
+    xmp.push_str(extra_schemas);
+    xmp.push_str("</rdf:RDF>\n</x:xmpmeta>\n<?xpacket end=\"w\"?>");
+
+    let mut stream = Stream::new(dictionary! { "Type" => "Metadata", "Subtype" => "XML" }, xmp.into_bytes());
+    // archival readers expect the packet to stay readable as plain text
+    stream.allows_compression = false;
+    stream
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -1,32 +1,112 @@
 use std::collections::HashMap;
 use std::io::prelude::*;
-use lopdf::{Object, Stream};
-use lopdf::content::{Content};
-use lopdf::dictionary;
-use zip::write::{FileOptions, ZipWriter};
-
-fn get_amount_missing_for_next_promo(qty_needed: i64, qty_claimed: i64) -> i64 {
-    if qty_claimed < qty_needed {
-        qty_needed - qty_claimed
-    } else if qty_claimed == qty_needed {
-        qty_needed
-    } else {
-        // 6
-        let promo_count_ceiling = qty_claimed / qty_needed + 1;
-        let remaining_needed = promo_count_ceiling * qty_needed - qty_claimed;
-        remaining_needed
-    }
-}
+use serde::{Serialize, Serializer};
 
-struct MissingPartNumber {
+/// Units still needed from one group of interchangeable part numbers.
+#[derive(Serialize, Debug, Clone)]
+pub struct MissingPartNumber {
     pub missing_part_numbers: Vec<String>,
     pub amount_needed: i64,
+    /// Units one full tier of this requirement asks for.
+    pub qty_per_tier: f64,
 }
-struct NeededSections {
+/// One still-needed part of a promo section; every entry must be satisfied to qualify again.
+#[derive(Serialize, Debug, Clone)]
+pub struct NeededSections {
+    #[serde(serialize_with = "serialize_join_groups")]
     pub missing_part_numbers: Vec<(AndOrType, Vec<MissingPartNumber>)>,
 }
 
-fn generate_missing_report_for_section(promo_section: &PromoSection) -> Vec<NeededSections> {
+#[derive(Serialize)]
+struct JoinGroup<'a> {
+    join_type: String,
+    items: &'a Vec<MissingPartNumber>,
+}
+
+// `serialize_with` passes the field itself, so this has to take the Vec
+#[allow(clippy::ptr_arg)]
+fn serialize_join_groups<S: Serializer>(
+    groups: &Vec<(AndOrType, Vec<MissingPartNumber>)>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(groups.iter().map(|(join_type, items)| JoinGroup {
+        join_type: join_type.to_string(),
+        items,
+    }))
+}
+
+/// Times qualified and what is still needed for one promo section of a customer.
+#[derive(Serialize, Debug, Clone)]
+pub struct SectionMissingReport {
+    /// One based promo number, as printed in the reports.
+    pub promo: usize,
+    pub times_qualified: i64,
+    pub needed: Vec<NeededSections>,
+    /// The most qualifications the promo pays for this section, when it has a cap.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maximum_qualifications: Option<u32>,
+    /// True once `times_qualified` is at the cap; `needed` is then empty.
+    pub maximum_reached: bool,
+    /// Cheapest purchases that qualify the section again, when prices are known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recommended: Option<Recommendation>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CustomerMissingReport {
+    pub customer: String,
+    pub sections: Vec<SectionMissingReport>,
+}
+
+/// Builds the missing report model for every customer, sorted by customer name.
+pub fn missing_report_model(
+    hsh: &HashMap<String, Promotion>,
+    prices: &PriceBook,
+    caps: &QualificationCaps,
+) -> Vec<CustomerMissingReport> {
+    let mut cust_names: Vec<_> = hsh.keys().collect();
+    cust_names.sort();
+    cust_names
+        .into_iter()
+        .map(|name| CustomerMissingReport {
+            customer: name.clone(),
+            sections: hsh[name]
+                .promo_sections
+                .iter()
+                .enumerate()
+                .map(|(sec_id, section)| {
+                    let maximum_reached = caps.reached(sec_id, section.times_section_qualified);
+                    let needed = if maximum_reached {
+                        Vec::new()
+                    } else {
+                        generate_missing_report_for_section(section)
+                    };
+                    SectionMissingReport {
+                        promo: sec_id + 1,
+                        times_qualified: section.times_section_qualified,
+                        maximum_qualifications: caps.cap(sec_id),
+                        maximum_reached,
+                        recommended: cheapest_path(name, &needed, prices),
+                        needed,
+                    }
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// Writes the missing report model as pretty printed json.
+pub fn write_missing_report_json<W: Write>(
+    hsh: &HashMap<String, Promotion>,
+    prices: &PriceBook,
+    caps: &QualificationCaps,
+    write_to: &mut W,
+) -> Result<(), PromoFinError> {
+    serde_json::to_writer_pretty(write_to, &missing_report_model(hsh, prices, caps))?;
+    Ok(())
+}
+
+pub fn generate_missing_report_for_section(promo_section: &PromoSection) -> Vec<NeededSections> {
     let mut rv: Vec<NeededSections> = Vec::new();
     for stl_nd_sec_ind in 0..promo_section.promo_parts_still_needed.len() {
         let part_index: usize = promo_section.promo_parts_still_needed[stl_nd_sec_ind];
@@ -41,14 +121,18 @@ fn generate_missing_report_for_section(promo_section: &PromoSection) -> Vec<Need
                 .total_qty;
             let qty_needed = promo_section_part.type_prod
                 [promo_section_part.type_prods_for_next_promo_needed[tpn_index] as usize]
-                .qty_needed as i64;
-            let rem = get_amount_missing_for_next_promo(qty_needed, total_qty);
+                .qty_needed as f64;
+            let next = TierRule::new(qty_needed).next_tier(total_qty as f64);
+            if next == NextTier::NotRequired {
+                continue;
+            }
             let missing = MissingPartNumber {
                 missing_part_numbers: promo_section_part.type_prod
                     [promo_section_part.type_prods_for_next_promo_needed[tpn_index] as usize]
                     .part_numbers
                     .clone(),
-                amount_needed: rem,
+                amount_needed: units_to_buy(next),
+                qty_per_tier: qty_needed,
             };
             missing_pn.push(missing);
         }
@@ -104,6 +188,7 @@ fn write_missing_report<W: Write>(
 
 pub fn display_missing_report<W: Write>(
     hsh: &mut HashMap<String, Promotion>,
+    caps: &QualificationCaps,
     write_to: &mut W,
 ) -> Result<(), std::io::Error> {
     let mut cust_names: Vec<_> = hsh.iter().map(|x| x.0).collect();
@@ -121,6 +206,10 @@ pub fn display_missing_report<W: Write>(
                 .as_bytes(),
             )?;
 
+            if caps.reached(sec_id, hsh[name].promo_sections[sec_id].times_section_qualified) {
+                write_to.write_all(b"Maximum reached\r\n")?;
+                continue;
+            }
             if hsh[name].promo_sections[sec_id].times_section_qualified == 0 {
                 write_to.write(format!("To get the promo you need to purchase:\r\n").as_bytes())?;
             } else {
@@ -134,191 +223,77 @@ pub fn display_missing_report<W: Write>(
     }
     Ok(())
 }
-struct PdfDox {
-    manager: Manager,
-}
-
-impl PdfDox {
-    pub fn new(width_inch: f64, height_inch: f64, dpi: f64, margin_top:f64, margin_bot:f64) -> Self {
-        Self {
-            manager: Manager::new(width_inch, height_inch, dpi, margin_top, margin_bot),
-        }
-    }
-}
-pub fn write_missing_report_to_pdf<W: Write>(
+/// Writes the missing report as csv with one row per customer, promo section and needed
+/// part-number group. Sections with nothing left to buy get a single row with the needed
+/// columns empty, and `Status` is `Maximum reached` for sections at their cap. Every row of a
+/// section repeats its recommended purchases, so filtering keeps them in view.
+pub fn write_missing_report_csv<W: Write>(
     hsh: &HashMap<String, Promotion>,
+    prices: &PriceBook,
+    caps: &QualificationCaps,
     write_to: &mut W,
-) -> Result<(), std::io::Error> {
-    let mut cust_names: Vec<_> = hsh.iter().map(|x| x.0).collect();
-    cust_names.sort();
-    let borders: Option<RefCell<Vec<Border>>> = Some(RefCell::new(Vec::new()));
-    let mut pdf_draw = PdfDrawInfo{ pdf: vec![] };
-    let mut dox = PdfDox::new( 8.5, 11.0, 72.0, 0.25,0.25 );
-
-    let mut should_new_page = false;
-
-    for name in cust_names {
-
-        let mut txt = TextBox::new(format!("For Customer: {}\r\n", name), FontInfo::new(16.0, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
-        let mut placement_handle = dox.manager.get_placement_handle(1..99, should_new_page);
-        should_new_page = true;
-        placement_handle.set_pixel_height(0.25 * 72.0);
-        placement_handle.draw(&mut txt, &mut pdf_draw, &borders);
-        let mut group = 0;
-        for sec_id in 0..hsh[name].promo_sections.len() {
-            let mut txt = TextBox::new(
-                format!(
-                    "Qualified {} times for Promo {}\r\n",
-                    &hsh[name].promo_sections[sec_id].times_section_qualified,
-                    sec_id + 1
-                ), FontInfo::new(14.0, Font::Helvetica), Some(TextAlignment::LeftCenter), None, None, None);
-            placement_handle = dox.manager.get_placement_handle(1..99, false);
-            placement_handle.set_pixel_height(0.25 * 72.0);
-            placement_handle.draw(&mut txt, &mut pdf_draw, &borders);
-
-            txt = TextBox::new(
-                "", FontInfo::new(14.0, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
-            placement_handle = dox.manager.get_placement_handle(1..99, false);
-            placement_handle.set_pixel_height(0.25 * 72.0);
-            placement_handle.draw(&mut txt, &mut pdf_draw, &borders);
-
-
-            if hsh[name].promo_sections[sec_id].times_section_qualified == 0 {
-                txt = TextBox::new(
-                    format!("To get the promo"), FontInfo::new(12.0, Font::Helvetica), Some(TextAlignment::LeftCenter), None, None, None);
-                placement_handle = dox.manager.get_placement_handle(1..99, false);
-                placement_handle.set_pixel_height(0.25 * 72.0);
-                placement_handle.draw(&mut txt, &mut pdf_draw, &borders);
-            } else {
-                txt = TextBox::new(
-                    format!("To get another promo"), FontInfo::new(12.0, Font::Helvetica), Some(TextAlignment::LeftCenter), None, None, None);
-                placement_handle = dox.manager.get_placement_handle(2..80, false);
-                placement_handle.set_pixel_height(0.25 * 72.0);
-                placement_handle.draw(&mut txt, &mut pdf_draw, &borders);
+) -> Result<(), PromoFinError> {
+    let mut writer = csv::Writer::from_writer(write_to);
+    writer.write_record(&[
+        "Customer",
+        "Promo",
+        "Times Qualified",
+        "Requirement",
+        "Join Type",
+        "Amount Needed",
+        "Part Numbers",
+        "Status",
+        "Recommended",
+    ])?;
+    for customer in missing_report_model(hsh, prices, caps) {
+        for section in &customer.sections {
+            let promo = section.promo.to_string();
+            let times_qualified = section.times_qualified.to_string();
+            let status = if section.maximum_reached { "Maximum reached" } else { "" };
+            let recommended = section.recommended.as_ref().map(describe_recommendation).unwrap_or_default();
+            let mut wrote_row = false;
+            for (requirement, needed) in section.needed.iter().enumerate() {
+                let requirement = (requirement + 1).to_string();
+                for (join_type, items) in &needed.missing_part_numbers {
+                    let join_type = join_type.to_string();
+                    for item in items {
+                        writer.write_record(&[
+                            customer.customer.as_str(),
+                            promo.as_str(),
+                            times_qualified.as_str(),
+                            requirement.as_str(),
+                            join_type.as_str(),
+                            item.amount_needed.to_string().as_str(),
+                            item.missing_part_numbers.join(", ").as_str(),
+                            status,
+                            recommended.as_str(),
+                        ])?;
+                        wrote_row = true;
+                    }
+                }
+            }
+            if !wrote_row {
+                writer.write_record(&[customer.customer.as_str(), promo.as_str(), times_qualified.as_str(), "", "", "", "", status, recommended.as_str()])?;
             }
-            let missing_section_data = generate_missing_report_for_section(&hsh[name].promo_sections[sec_id]);
-
-            write_missing_report_to_pdf_new(1..99,&missing_section_data, &mut pdf_draw, &borders, &mut dox, Some(group))?;
-
-            group = group + 1;
-            txt = TextBox::new(
-                "", FontInfo::new(10.0, Font::Helvetica), Some(TextAlignment::CenterCenter), None, None, None);
-            placement_handle = dox.manager.get_placement_handle(8..92, false);
-            placement_handle.set_pixel_height(0.30 * 72.0);
-            placement_handle.draw(&mut txt, &mut pdf_draw, &borders);
-
-
-        }
-        txt = TextBox::new(
-            "", FontInfo::new(10.0, Font::Helvetica), Some(TextAlignment::CenterCenter), None, None, None);
-        placement_handle = dox.manager.get_placement_handle(8..92, false);
-        placement_handle.set_pixel_height(0.25 * 72.0);
-        placement_handle.draw(&mut txt, &mut pdf_draw, &borders);
-
-    }
-
-
-    match borders {
-        Some(brd) => {
-
-            for border in brd.into_inner().into_iter() {
-
-                draw_rectangle(&mut pdf_draw,
-                               &border.rec,
-                               border.pixel_size,
-                               border.color);
-            };
-        },
-        None => {
-
-        }
-    }
-
-
-    for group_rec in dox.manager.get_groups() {
-        for page_index in 0..group_rec.1.len() {
-
-            let mut pl: PlacementInfo = PlacementInfo::default();
-            pl.page_number = page_index;
-            pl.rec = group_rec.1[page_index].clone();
-            pl.page_size_info = dox.manager.get_page_info();
-            let col = if group_rec.0 == 0 {
-                (1.0,0.0,0.0)
-            } else if group_rec.0 == 1 {
-                (0.0,1.0,0.0)
-            } else if group_rec.0 == 2 {
-                (1.0,1.0,0.0)
-            } else if group_rec.0 == 3 {
-                (1.0,0.0,1.0)
-            } else {
-                (0.0,0.0,0.0)
-            };
-            let grw_amt = 6.0;
-            pl.rec.x = pl.rec.x - grw_amt;
-            pl.rec.width = pl.rec.width + 2.0 * grw_amt;
-            pl.rec.y = pl.rec.y - grw_amt;
-            pl.rec.height = pl.rec.height + 2.0 * grw_amt;
-            draw_rectangle(&mut pdf_draw,
-                           &pl,
-                           3.0,
-                           col);
         }
     }
-
-
-    let mut doc = lopdf::Document::with_version("1.5");
-    let pages_id = doc.new_object_id();
-
-    let resources_id = create_font_recource_id(&mut doc);
-    let mut v:Vec<lopdf::Object> = Vec::new();
-
-    for page in 0..dox.manager.get_page_cnt() + 1 {
-        let content = Content {
-            operations: pdf_draw.pdf[page].clone()//draw_data_pdf(0.2, 1.0, &mut columns)
-        };
-        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
-        let page_id = doc.add_object(dictionary! {
-            "Type" => "Page",
-            "Parent" => pages_id,
-            "Contents" => content_id,
-            });
-        v.push( page_id.into() )
-    };
-    let page_count = v.len() as i32;
-
-    let pages = dictionary! {
-		"Type" => "Pages",
-		"Kids" => v,
-		"Count" => page_count,
-		"Resources" => resources_id,
-		"MediaBox" => vec![0.into(), 0.into(), (dox.manager.get_page_pixel_dims().0).into(), (dox.manager.get_page_pixel_dims().1).into()],
-	};
-    doc.objects.insert(pages_id, Object::Dictionary(pages));
-    let catalog_id = doc.add_object(dictionary! {
-		"Type" => "Catalog",
-		"Pages" => pages_id,
-	});
-    doc.trailer.set("Root", catalog_id);
-    doc.compress();
-    doc.save_to(write_to).unwrap();
-
+    writer.flush()?;
     Ok(())
 }
-pub fn write_missing_report_to_pdf_per_customer<W: Write>(
+
+/// Draws the heading and every promo section of one customer's missing report.
+fn draw_customer_missing_report(
+    builder: &mut PdfReportBuilder,
     hsh: &HashMap<String, Promotion>,
-    customer: &String,
-    write_to: &mut W,
-) -> Result<(), std::io::Error> {
-    let mut cust_names: Vec<_> = hsh.iter().map(|x| x.0).collect();
-    cust_names.sort();
-    let borders: Option<RefCell<Vec<Border>>> = Some(RefCell::new(Vec::new()));
-    let mut pdf_draw = PdfDrawInfo{ pdf: vec![] };
-    let mut dox = PdfDox::new( 8.5, 11.0, 72.0, 0.25,0.25 );
-
-    let mut txt = TextBox::new(format!("For Customer: {}\r\n", customer), FontInfo::new(16.0, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
-    let mut placement_handle = dox.manager.get_placement_handle(1..99, false);
-    placement_handle.set_pixel_height(0.25 * 72.0);
-    placement_handle.draw(&mut txt, &mut pdf_draw, &borders);
+    customer: &str,
+    fonts: &FontSizes,
+    prices: &PriceBook,
+    caps: &QualificationCaps,
+    new_page: bool,
+) -> Result<(), PromoFinError> {
+    let mut txt = TextBox::new(format!("For Customer: {}\r\n", customer), FontInfo::new(fonts.title, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
+    builder.text_row(1..99, new_page, 0.25, TextStyle::Title, &mut txt);
+    builder.start_customer(customer);
     let mut group = 0;
     for sec_id in 0..hsh[customer].promo_sections.len() {
         let mut txt = TextBox::new(
@@ -326,138 +301,123 @@ pub fn write_missing_report_to_pdf_per_customer<W: Write>(
                 "Qualified {} times for Promo {}\r\n",
                 &hsh[customer].promo_sections[sec_id].times_section_qualified,
                 sec_id + 1
-            ), FontInfo::new(14.0, Font::Helvetica), Some(TextAlignment::LeftCenter), None, None, None);
-        placement_handle = dox.manager.get_placement_handle(1..99, false);
-        placement_handle.set_pixel_height(0.25 * 72.0);
-        placement_handle.draw(&mut txt, &mut pdf_draw, &borders);
+            ), FontInfo::new(fonts.heading, Font::Helvetica), Some(TextAlignment::LeftCenter), None, None, None);
+        builder.text_row(1..99, false, 0.25, TextStyle::Heading, &mut txt);
+        builder.start_section(&format!("Promo {}", sec_id + 1));
+        builder.spacer(1..99, 0.25, TextStyle::Heading);
 
-        txt = TextBox::new(
-            "", FontInfo::new(14.0, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
-        placement_handle = dox.manager.get_placement_handle(1..99, false);
-        placement_handle.set_pixel_height(0.25 * 72.0);
-        placement_handle.draw(&mut txt, &mut pdf_draw, &borders);
+        if caps.reached(sec_id, hsh[customer].promo_sections[sec_id].times_section_qualified) {
+            txt = TextBox::new(
+                "Maximum reached".to_owned(), FontInfo::new(fonts.body, Font::Helvetica), Some(TextAlignment::LeftCenter), None, None, None);
+            builder.text_row(2..80, false, 0.25, TextStyle::Body, &mut txt);
+            group += 1;
+            builder.spacer(8..92, 0.30, TextStyle::Small);
+            continue;
+        }
 
         if hsh[customer].promo_sections[sec_id].times_section_qualified == 0 {
             txt = TextBox::new(
-                format!("To get the promo"), FontInfo::new(12.0, Font::Helvetica), Some(TextAlignment::LeftCenter), None, None, None);
-            placement_handle = dox.manager.get_placement_handle(1..99, false);
-            placement_handle.set_pixel_height(0.25 * 72.0);
-            placement_handle.draw(&mut txt, &mut pdf_draw, &borders);
+                "To get the promo".to_owned(), FontInfo::new(fonts.body, Font::Helvetica), Some(TextAlignment::LeftCenter), None, None, None);
+            builder.text_row(1..99, false, 0.25, TextStyle::Body, &mut txt);
         } else {
             txt = TextBox::new(
-                format!("To get another promo"), FontInfo::new(12.0, Font::Helvetica), Some(TextAlignment::LeftCenter), None, None, None);
-            placement_handle = dox.manager.get_placement_handle(2..80, false);
-            placement_handle.set_pixel_height(0.25 * 72.0);
-            placement_handle.draw(&mut txt, &mut pdf_draw, &borders);
+                "To get another promo".to_owned(), FontInfo::new(fonts.body, Font::Helvetica), Some(TextAlignment::LeftCenter), None, None, None);
+            builder.text_row(2..80, false, 0.25, TextStyle::Body, &mut txt);
         }
         let missing_section_data = generate_missing_report_for_section(&hsh[customer].promo_sections[sec_id]);
 
-        write_missing_report_to_pdf_new(1..99,&missing_section_data, &mut pdf_draw, &borders, &mut dox, Some(group))?;
+        write_missing_report_to_pdf_new(1..99, &missing_section_data, builder, fonts, Some(group))?;
+
+        if let Some(recommendation) = cheapest_path(customer, &missing_section_data, prices) {
+            let mut txt = TextBox::new(describe_recommendation(&recommendation), FontInfo::new(fonts.small, Font::Helvetica), Some(TextAlignment::LeftCenter), None, None, None);
+            builder.text_row(2..98, false, 0.25, TextStyle::Small, &mut txt);
+        }
 
         group = group + 1;
-        txt = TextBox::new(
-            "", FontInfo::new(10.0, Font::Helvetica), Some(TextAlignment::CenterCenter), None, None, None);
-        placement_handle = dox.manager.get_placement_handle(8..92, false);
-        placement_handle.set_pixel_height(0.30 * 72.0);
-        placement_handle.draw(&mut txt, &mut pdf_draw, &borders);
+        builder.spacer(8..92, 0.30, TextStyle::Small);
     }
+    Ok(())
+}
 
-    match borders {
-        Some(brd) => {
-
-            for border in brd.into_inner().into_iter() {
+pub fn write_missing_report_to_pdf<W: Write>(
+    hsh: &HashMap<String, Promotion>,
+    prices: &PriceBook,
+    caps: &QualificationCaps,
+    layout: &LayoutConfig,
+    write_to: &mut W,
+) -> Result<(), PromoFinError> {
+    let mut cust_names: Vec<_> = hsh.iter().map(|x| x.0).collect();
+    cust_names.sort();
+    let mut builder = PdfReportBuilder::new(&layout.missing_page, layout)?;
+    builder.enable_outline();
+    let period = &layout.decorations.promo_period;
+    builder.describe(DocumentInfo {
+        title: "Missing Report".to_owned(),
+        subject: with_period("Purchases missing for the next promo, all customers".to_owned(), period),
+        keywords: vec!["Missing Report".to_owned(), period.clone()],
+    });
 
-                draw_rectangle(&mut pdf_draw,
-                               &border.rec,
-                               border.pixel_size,
-                               border.color);
-            };
-        },
-        None => {
+    let mut should_new_page = false;
 
-        }
+    for name in cust_names {
+        draw_customer_missing_report(&mut builder, hsh, name, &layout.fonts, prices, caps, should_new_page)?;
+        should_new_page = true;
+        builder.spacer(8..92, 0.25, TextStyle::Small);
     }
 
-    for group_rec in dox.manager.get_groups() {
-        for page_index in 0..group_rec.1.len() {
-
-            let mut pl: PlacementInfo = PlacementInfo::default();
-            pl.page_number = page_index;
-            pl.rec = group_rec.1[page_index].clone();
-            pl.page_size_info = dox.manager.get_page_info();
-            let mut col = if group_rec.0 == 0 {
-                (1.0,0.0,0.0)
-            } else if group_rec.0 == 1 {
-                (0.0,1.0,0.0)
-            } else if group_rec.0 == 2 {
-                (1.0,1.0,0.0)
-            } else if group_rec.0 == 3 {
-                (1.0,0.0,1.0)
-            } else {
-                (0.0,0.0,0.0)
-            };
-            col = (0.0,0.0,0.0);
-            let grw_amt = 6.0;
-            pl.rec.x = pl.rec.x - grw_amt;
-            pl.rec.width = pl.rec.width + 2.0 * grw_amt;
-            pl.rec.y = pl.rec.y - grw_amt;
-            pl.rec.height = pl.rec.height + 2.0 * grw_amt;
-            draw_rectangle(&mut pdf_draw,
-                           &pl,
-                           3.0,
-                           col);
+    builder.draw_groups(6.0, 3.0, |group| {
+        if group == 0 {
+            (1.0,0.0,0.0)
+        } else if group == 1 {
+            (0.0,1.0,0.0)
+        } else if group == 2 {
+            (1.0,1.0,0.0)
+        } else if group == 3 {
+            (1.0,0.0,1.0)
+        } else {
+            (0.0,0.0,0.0)
         }
-    }
+    });
 
+    builder.finish(write_to)
+}
+pub fn write_missing_report_to_pdf_per_customer<W: Write>(
+    hsh: &HashMap<String, Promotion>,
+    customer: &str,
+    prices: &PriceBook,
+    caps: &QualificationCaps,
+    layout: &LayoutConfig,
+    write_to: &mut W,
+) -> Result<(), PromoFinError> {
+    let mut builder = PdfReportBuilder::new(&layout.missing_page, layout)?;
+    let period = &layout.decorations.promo_period;
+    builder.describe(DocumentInfo {
+        title: format!("{} Missing Report", customer),
+        subject: with_period(format!("Purchases missing for the next promo for {}", customer), period),
+        keywords: vec![customer.to_owned(), "Missing Report".to_owned(), period.clone()],
+    });
+    builder.protect_for(customer)?;
 
-    let mut doc = lopdf::Document::with_version("1.5");
-    let pages_id = doc.new_object_id();
+    draw_customer_missing_report(&mut builder, hsh, customer, &layout.fonts, prices, caps, false)?;
 
-    let resources_id = create_font_recource_id(&mut doc);
-    let mut v:Vec<lopdf::Object> = Vec::new();
+    builder.draw_groups(6.0, 3.0, |_| (0.0,0.0,0.0));
 
-    for page in 0..dox.manager.get_page_cnt() + 1 {
-        let content = Content {
-            operations: pdf_draw.pdf[page].clone()//draw_data_pdf(0.2, 1.0, &mut columns)
-        };
-        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
-        let page_id = doc.add_object(dictionary! {
-            "Type" => "Page",
-            "Parent" => pages_id,
-            "Contents" => content_id,
-            });
-        v.push( page_id.into() )
-    };
-    let page_count = v.len() as i32;
-
-    let pages = dictionary! {
-		"Type" => "Pages",
-		"Kids" => v,
-		"Count" => page_count,
-		"Resources" => resources_id,
-		"MediaBox" => vec![0.into(), 0.into(), (dox.manager.get_page_pixel_dims().0).into(), (dox.manager.get_page_pixel_dims().1).into()],
-	};
-    doc.objects.insert(pages_id, Object::Dictionary(pages));
-    let catalog_id = doc.add_object(dictionary! {
-		"Type" => "Catalog",
-		"Pages" => pages_id,
-	});
-    doc.trailer.set("Root", catalog_id);
-    doc.compress();
-    doc.save_to(write_to).unwrap();
-
-    Ok(())
+    builder.finish(write_to)
 }
 
+/// Part numbers per row of the missing report grid, which spans half of its placement range.
+const GRID_CELLS: usize = 5;
+
 fn write_missing_report_to_pdf_new( placement_range: Range<usize>,
     missing_report: &Vec<NeededSections>,
-    pdf_draw: &mut PdfDrawInfo, borders: &Option<RefCell<Vec<Border>>>, dox: &mut PdfDox, group: Option<usize>,
-) -> Result<(), std::io::Error> {
-    let col_size: Vec<usize> = vec![10, 10, 10, 10, 10];//vec![9, 10, 11, 12];
+    builder: &mut PdfReportBuilder, fonts: &FontSizes, group: Option<usize>,
+) -> Result<(), PromoFinError> {
+    let width_of_range = placement_range.end - placement_range.start;
+    let col_size: Vec<usize> = scale_columns(&[1.0; GRID_CELLS], width_of_range / 2);
     let col_size_len = col_size.len();
     let col_sum = col_size.clone().into_iter().sum::<usize>();
-    let width_of_range = placement_range.end - placement_range.start;
     let half_range = Range{start: width_of_range / 2 - col_sum / 2, end: width_of_range / 2 + col_sum / 2};
+    let (purchase_color, border_color) = (builder.theme().primary_color, builder.theme().secondary_color);
 
     for sec_index in 0..missing_report.len() {
         let sec = &missing_report[sec_index];
@@ -465,7 +425,6 @@ fn write_missing_report_to_pdf_new( placement_range: Range<usize>,
         let mut data: Vec<RowData> = Vec::new();
         let mut row_on = 0;
 
-        let purchase_color = (0.3,0.3,0.9);
         for missing_index in 0..sec.missing_part_numbers.len() {
 
             let (join_type, items) = &sec.missing_part_numbers[missing_index];
@@ -521,14 +480,15 @@ fn write_missing_report_to_pdf_new( placement_range: Range<usize>,
               //  row_on = row_on + 1;
 
             }
-            let mut placement_handle = dox.manager.get_placement_handle(Range { start: half_range.start, end: half_range.end }, false);
+            let item_alignments = vec![TextAlignment::CenterCenter; 6];
+            let mut placement_handle = builder.manager.get_placement_handle(Range { start: half_range.start, end: half_range.end }, false);
 
             let mut list_box = ListBox::new(&data,
                                             col_size.clone(),
                                             None,
-                                            &mut dox.manager,
-                                            FontInfo::new(12.0, Font::Helvetica),
-                                            FontInfo::new(12.0, Font::Helvetica),
+                                            &mut builder.manager,
+                                            FontInfo::new(fonts.body, Font::Helvetica),
+                                            FontInfo::new(fonts.body, Font::Helvetica),
                                             ListBoxBorder::All(2.0,3.0), group
             );
 
@@ -536,9 +496,11 @@ fn write_missing_report_to_pdf_new( placement_range: Range<usize>,
 
                 //list_box.add_solid_row(r.0,r.1);
    //         }
+            list_box.set_border_color(border_color);
             list_box.set_row_types(vec![TypeOfItem::String, TypeOfItem::String,TypeOfItem::String, TypeOfItem::String, TypeOfItem::String, TypeOfItem::String]);
-            list_box.set_item_column_alignments(vec![TextAlignment::CenterCenter, TextAlignment::CenterCenter,TextAlignment::CenterCenter, TextAlignment::CenterCenter, TextAlignment::CenterCenter, TextAlignment::CenterCenter, ]);
-            placement_handle.draw(&mut list_box, pdf_draw, &borders);
+            list_box.set_item_column_alignments(item_alignments);
+            builder.use_styles(&[TextStyle::Body]);
+            placement_handle.draw(&mut list_box, &mut builder.pdf_draw, &builder.borders);
 
 
             /*
@@ -574,99 +536,483 @@ fn write_missing_report_to_pdf_new( placement_range: Range<usize>,
 
         if sec_index < missing_report.len() - 1 {
        //     data[row_on] = vec!["".to_owned(), "".to_owned(),"And".into(),"".to_owned(),"".to_owned()];
-            let mut placement_handle = dox.manager.get_placement_handle(Range { start: half_range.start, end: half_range.end }, false);
+            let mut placement_handle = builder.manager.get_placement_handle(Range { start: half_range.start, end: half_range.end }, false);
             let mut txt = TextBox::new(
                 format!("And"),
-                FontInfo::new(12.0, Font::Helvetica), Some(TextAlignment::CenterCenter), None, None, group);
+                FontInfo::new(fonts.body, Font::Helvetica), Some(TextAlignment::CenterCenter), None, None, group);
           //  placement_handle = dox.manager.get_placement_handle(Range { start: placement_range.start, end: placement_range.end }, false);
-            placement_handle.set_pixel_height(0.30 * 72.0);
-            placement_handle.draw(&mut txt, pdf_draw, &borders);
+            placement_handle.set_pixel_height(0.30 * builder.dpi());
+            builder.use_styles(&[TextStyle::Body]);
+            placement_handle.draw(&mut txt, &mut builder.pdf_draw, &builder.borders);
         }
         //       write_to.write(format!("\r\n").as_bytes())?;
     } //end sec
     Ok(())
 }
 
-use std::cell::RefCell;
-use backfat::container::rectangle::{Border};
 use backfat::container_objects::text_box::{TextBox, TextAlignment};
 use backfat::font::font_info::FontInfo;
-use backfat::font::font_sizes::{Font, create_font_recource_id};
-use backfat::container::manager::{Manager};
+use backfat::font::font_sizes::Font;
 use backfat::container_objects::list_box::{TypeOfItem, ListBoxBorder, ListBox, RowData, RowDataTypes};
 use std::ops::Range;
-use backfat::container_objects::lines::draw_rectangle;
-use backfat::container::placement_info::PlacementInfo;
 use promo_input::general::promo_json::{Promotion, PromoSection};
 use promo_input::general::and_or::AndOrType;
 use promo_input::general::data::load_promo;
-use crate::pdf::{write_rows_to_pdf_container, PdfDrawInfo};
+use crate::pdf::{scale_columns, with_period, write_rows_to_pdf_container, PdfReportBuilder};
+use crate::metadata::DocumentInfo;
+use crate::error::PromoFinError;
+use crate::archive::{ArchiveLayout, unique_customer_names};
+use crate::output::{ReportSink, ZipSink, DirectorySink};
+use crate::xlsx::write_detail_workbook;
+use crate::html::write_html_site;
+use crate::near_miss::{near_miss_ranking, write_near_miss_csv, write_near_miss_to_pdf};
+use crate::payout::{payout_register, write_payout_register_csv, write_payout_register_to_pdf};
+use crate::config::{LayoutConfig, FontSizes, ReportSelection, RunConfig, TextStyle};
+use crate::recommend::{cheapest_path, describe_recommendation, PriceBook, Recommendation};
+use crate::tiers::{units_to_buy, NextTier, QualificationCaps, TierRule};
+
+
+/// Column positions of the qualifying detail fields in the loaded sales rows.
+#[derive(Debug, Clone)]
+pub struct DetailColumns {
+    pub ship_date: usize,
+    pub customer_name: usize,
+    pub order_number: usize,
+    pub qty: usize,
+    pub part_number: usize,
+    pub part_number_desc: usize,
+    pub sales: usize,
+}
+
+impl DetailColumns {
+    /// Column indexes in the order the detail reports print them.
+    pub fn in_detail_order(&self) -> [usize; 7] {
+        [self.ship_date, self.customer_name, self.order_number, self.qty, self.part_number, self.part_number_desc, self.sales]
+    }
+}
+
+/// Promo results for every customer, loaded once and shared by all report writers.
+pub struct PromoRun {
+    pub data: HashMap<String, Promotion>,
+    pub columns: DetailColumns,
+    /// Unit prices from the qualifying rows, plus any price list loaded afterwards.
+    pub prices: PriceBook,
+    /// Qualification caps from the promo json, already applied to `data`.
+    pub caps: QualificationCaps,
+    /// Qualification counts per customer and section from before the caps were applied.
+    pub uncapped_times: HashMap<String, Vec<i64>>,
+    /// Line in the sales export of each qualifying row, keyed by its cells as [`PromoRun::detail_rows`]
+    /// returns them. Identical rows share the line of the first.
+    pub sales_lines: HashMap<Vec<String>, usize>,
+}
 
+/// Loads the sales input and evaluates it against the promo json.
+pub fn load_promo_run(input_file: &str, json_promo_file: &str) -> Result<PromoRun, PromoFinError> {
+    let completed_promo = load_promo( input_file, json_promo_file).map_err(|e| PromoFinError::Load {
+        input_file: input_file.to_owned(),
+        promo_file: json_promo_file.to_owned(),
+        message: e.to_string(),
+    })?;
+    let columns = DetailColumns {
+        ship_date: completed_promo.ship_date_column_index,
+        customer_name: completed_promo.customer_name_column_index,
+        order_number: completed_promo.order_number_column_index,
+        qty: completed_promo.qty_column_index,
+        part_number: completed_promo.part_number_column_index,
+        part_number_desc: completed_promo.part_number_desc_column_index,
+        sales: completed_promo.sales_column_index,
+    };
+    let mut data = completed_promo.data;
+    let caps = QualificationCaps::load(json_promo_file)?;
+    let uncapped_times = caps.apply(&mut data);
+    let prices = PriceBook::from_history(&data, &columns);
+    let sales_lines = read_sales_lines(input_file, &columns)?;
+    Ok(PromoRun { data, columns, prices, caps, uncapped_times, sales_lines })
+}
+
+/// Numbers the rows of the sales export by line, so bad cells can be pointed out in the file.
+fn read_sales_lines(input_file: &str, columns: &DetailColumns) -> Result<HashMap<Vec<String>, usize>, PromoFinError> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_path(input_file)?;
+    let mut sales_lines = HashMap::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = match record.position() {
+            Some(position) => position.line() as usize,
+            None => continue,
+        };
+        let cells: Option<Vec<String>> = columns.in_detail_order().iter().map(|&index| record.get(index).map(str::to_owned)).collect();
+        if let Some(cells) = cells {
+            sales_lines.entry(cells).or_insert(line);
+        }
+    }
+    Ok(sales_lines)
+}
+
+impl PromoRun {
+    /// How often `customer` qualified for section `section_index` before the promo's cap.
+    pub fn times_before_cap(&self, customer: &str, section_index: usize) -> i64 {
+        self.uncapped_times
+            .get(customer)
+            .and_then(|times| times.get(section_index))
+            .cloned()
+            .unwrap_or_else(|| self.data[customer].promo_sections[section_index].times_section_qualified)
+    }
+
+    /// Qualifying purchase rows of a section, one group per type_prod, each sorted by ship date.
+    pub fn detail_rows(&self, section: &PromoSection) -> Vec<Vec<Vec<String>>> {
+        let mut parts_ret: Vec<Vec<Vec<String>>> = Vec::new();
+
+        for part in &section.part {
+            for type_prod in &part.type_prod {
+                let mut all_rows2: Vec<Vec<String>> = Vec::new();
+
+                for row in &type_prod.found_numbers {
+                    all_rows2.push(self.columns.in_detail_order().iter().map(|&index| row[index].value.clone()).collect());
+                }
+                all_rows2.sort_by(|x, y| x[0].cmp(&y[0]));
+                parts_ret.push(all_rows2);
+            }
+        }
+        parts_ret
+    }
+}
 
 pub fn run_missing_reports<W: Write>(
     input_file: &str,
     json_promo_file: &str,
     output_file: Option<&mut W>,
-    zip_path: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+    zip_path: Option<&str>,
+) -> Result<(), PromoFinError> {
+    let mut sinks: Vec<Box<dyn ReportSink>> = Vec::new();
+    if let Some(zip_path) = zip_path {
+        sinks.push(Box::new(ZipSink::create(zip_path)?));
+    }
+    run_missing_reports_to_sinks(
+        input_file,
+        json_promo_file,
+        output_file,
+        &mut sinks,
+        &ArchiveLayout::default(),
+        &LayoutConfig::default(),
+        &ReportSelection::default(),
+    )
+}
 
-//    std::fs::create_dir_all(&path)?;
-    let zip_file = std::fs::File::create(zip_path.to_owned() ).expect("Couldn't create file");
-    let mut zip_file_writer = ZipWriter::new(zip_file);
+fn create_file(path: &str) -> Result<std::fs::File, PromoFinError> {
+    std::fs::File::create(path).map_err(|source| PromoFinError::CreateFile { path: path.to_owned(), source })
+}
 
-    let completed_promo = load_promo( input_file, json_promo_file)?;
-    if let Some( full_file ) = output_file {
-        write_missing_report_to_pdf(  &completed_promo.data, full_file )?;
+/// Runs the reports described by a run config, creating the output files it names.
+pub fn run_with_config(config: &RunConfig) -> Result<(), PromoFinError> {
+    let mut promo_run = load_promo_run(&config.input_file, &config.promo_file)?;
+    if let Some(path) = &config.price_list {
+        promo_run.prices.load_price_list(path)?;
+    }
+
+    if let (Some(path), true) = (&config.output.missing_report_pdf, config.reports.combined_missing) {
+        write_missing_report_to_pdf(&promo_run.data, &promo_run.prices, &promo_run.caps, &config.layout, &mut create_file(path)?)?;
+    }
+    if let Some(path) = &config.output.missing_report_json {
+        write_missing_report_json(&promo_run.data, &promo_run.prices, &promo_run.caps, &mut create_file(path)?)?;
+    }
+    if let Some(path) = &config.output.missing_report_csv {
+        write_missing_report_csv(&promo_run.data, &promo_run.prices, &promo_run.caps, &mut create_file(path)?)?;
+    }
+    if let Some(path) = &config.output.detail_xlsx {
+        write_detail_workbook(&promo_run, config.output.detail_xlsx_sheets, create_file(path)?)?;
+    }
+    if let Some(directory) = &config.output.html_directory {
+        write_html_site(&promo_run, &mut DirectorySink::new(directory))?;
+    }
+    if config.output.payout_register_pdf.is_some() || config.output.payout_register_csv.is_some() {
+        let register = payout_register(&promo_run, &config.payouts)?;
+        if let Some(path) = &config.output.payout_register_pdf {
+            write_payout_register_to_pdf(&register, &config.layout, &mut create_file(path)?)?;
+        }
+        if let Some(path) = &config.output.payout_register_csv {
+            write_payout_register_csv(&register, &mut create_file(path)?)?;
+        }
+    }
+    if config.output.near_miss_pdf.is_some() || config.output.near_miss_csv.is_some() {
+        let ranking = near_miss_ranking(&promo_run, &config.near_miss)?;
+        if let Some(path) = &config.output.near_miss_pdf {
+            write_near_miss_to_pdf(&ranking, &config.layout, &mut create_file(path)?)?;
+        }
+        if let Some(path) = &config.output.near_miss_csv {
+            write_near_miss_csv(&ranking, &mut create_file(path)?)?;
+        }
     }
 
-    for (customer, promo) in &completed_promo.data {
-        let mut v = Vec::new();
-        write_missing_report_to_pdf_per_customer(  &completed_promo.data, customer,&mut v )?;
-        let write_file = format!("Missing_Reports\\{} Missing Report.pdf", customer);
-        zip_file_writer.start_file(write_file.to_owned(), FileOptions::default())?;
+    let mut sinks: Vec<Box<dyn ReportSink>> = Vec::new();
+    if config.reports.customer_missing || config.reports.detail {
+        if let Some(zip_path) = &config.output.zip {
+            sinks.push(Box::new(ZipSink::create(zip_path)?));
+        }
+        if let Some(directory) = &config.output.directory {
+            sinks.push(Box::new(DirectorySink::new(directory)));
+        }
+    }
+    write_customer_reports(&promo_run, &mut sinks, &config.output.archive_layout, &config.layout, &config.reports)
+}
 
-        zip_file_writer.write(&v)?;
+/// Loads the promo data, writes the combined missing report to `output_file` and every
+/// per-customer report to each of `sinks`, naming entries with `archive_layout`.
+pub fn run_missing_reports_to_sinks<W: Write>(
+    input_file: &str,
+    json_promo_file: &str,
+    output_file: Option<&mut W>,
+    sinks: &mut [Box<dyn ReportSink>],
+    archive_layout: &ArchiveLayout,
+    layout: &LayoutConfig,
+    reports: &ReportSelection,
+) -> Result<(), PromoFinError> {
+
+    let promo_run = load_promo_run(input_file, json_promo_file)?;
+    if let Some( full_file ) = output_file {
+        write_missing_report_to_pdf(  &promo_run.data, &promo_run.prices, &promo_run.caps, layout, full_file )?;
+    }
+    write_customer_reports(&promo_run, sinks, archive_layout, layout, reports)
+}
+
+/// Writes the per-customer missing reports and qualifying detail to each of `sinks`.
+pub fn write_customer_reports(
+    promo_run: &PromoRun,
+    sinks: &mut [Box<dyn ReportSink>],
+    archive_layout: &ArchiveLayout,
+    layout: &LayoutConfig,
+    reports: &ReportSelection,
+) -> Result<(), PromoFinError> {
+    if sinks.is_empty() {
+        return Ok(());
+    }
+    let mut layout = layout.clone();
+    layout.encryption.load_passwords()?;
+    let layout = &layout;
+
+    let customer_names = unique_customer_names(promo_run.data.keys());
+
+    for (customer, promo) in &promo_run.data {
+        let customer_name = &customer_names[customer];
+        if reports.customer_missing {
+            let mut v = Vec::new();
+            write_missing_report_to_pdf_per_customer(  &promo_run.data, customer, &promo_run.prices, &promo_run.caps, layout, &mut v )?;
+            let write_file = archive_layout.missing_report_path(customer_name);
+            for sink in sinks.iter_mut() {
+                sink.write_entry(&write_file, &v)?;
+            }
+        }
+        if !reports.detail {
+            continue;
+        }
         for section_index in 0..promo.promo_sections.len() {
             let section = &promo.promo_sections[section_index];
             if section.times_section_qualified > 0 {
-                let mut parts_ret: Vec<Vec<Vec<String>>> = Vec::new();
-
-                for part in &section.part {
-                    for type_prod in &part.type_prod {
-                        let mut all_rows2: Vec<Vec<String>> = Vec::new();
-
-                        for row in &type_prod.found_numbers {
-                            all_rows2.push(vec![
-                                row[completed_promo.ship_date_column_index].value.clone(),
-                                row[completed_promo.customer_name_column_index].value.clone(),
-                                row[completed_promo.order_number_column_index].value.clone(),
-                                row[completed_promo.qty_column_index].value.clone(),
-                                row[completed_promo.part_number_column_index].value.clone(),
-                                row[completed_promo.part_number_desc_column_index].value.clone(),
-                                row[completed_promo.sales_column_index].value.clone(),
-                            ]);
-                        }
-                        all_rows2.sort_by(|x, y| x[0].cmp(&y[0]));
-                        parts_ret.push(all_rows2);
-                    }
-                }
+                let parts_ret = promo_run.detail_rows(section);
 
+                let mut v = Vec::new();
                 write_rows_to_pdf_container(
                     customer,
                     section_index,
                     section.times_section_qualified,
-                    parts_ret.clone(),
+                    parts_ret,
+                    &promo_run.sales_lines,
+                    layout,
                     &mut v,
 
                 )?;
-                let write_file = format!("{}\\Promo#{}.pdf", customer, section_index.to_string());
-
-                zip_file_writer.start_file(write_file.to_owned(), FileOptions::default())?;
-                zip_file_writer.write(&v)?;
+                let write_file = archive_layout.detail_path(customer_name, section_index);
+                for sink in sinks.iter_mut() {
+                    sink.write_entry(&write_file, &v)?;
+                }
 
             }
         }
     }
-    zip_file_writer.finish()?;
+    for sink in sinks.iter_mut() {
+        sink.finish()?;
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::MemorySink;
+    use crate::test_fixtures::{columns, promo_run, promotion, sale, section};
+
+    #[test]
+    fn customer_reports_are_one_document_per_entry() {
+        let run = promo_run(vec![
+            ("Acme", promotion(vec![section(1, 5, 6, "AB-100", vec![sale("Acme", "6", "AB-100", "2.50")])])),
+            ("Bolt", promotion(vec![section(2, 5, 12, "AB-100", vec![sale("Bolt", "12", "AB-100", "2.50")])])),
+            ("Crane", promotion(vec![section(0, 5, 1, "AB-100", vec![sale("Crane", "1", "AB-100", "2.50")])])),
+        ]);
+        let memory = MemorySink::new();
+        let mut sinks: Vec<Box<dyn ReportSink>> = vec![Box::new(memory.clone())];
+        write_customer_reports(
+            &run,
+            &mut sinks,
+            &ArchiveLayout::default(),
+            &LayoutConfig::default(),
+            &ReportSelection::default(),
+        )
+        .unwrap();
+
+        let entries = memory.entries();
+        // a missing report per customer, detail only for the two that qualified
+        assert_eq!(entries.len(), 5);
+        let layout = ArchiveLayout::default();
+        for customer in &["Acme", "Bolt", "Crane"] {
+            assert!(memory.get(&layout.missing_report_path(customer)).is_some());
+        }
+        assert!(memory.get(&layout.detail_path("Crane", 0)).is_none());
+        for (path, data) in &entries {
+            let doc = lopdf::Document::load_mem(data).unwrap_or_else(|e| panic!("{} is not a pdf: {}", path, e));
+            assert_eq!(doc.get_pages().len(), 1, "{}", path);
+            // a second document appended to the buffer would leave a second trailer behind
+            assert_eq!(String::from_utf8_lossy(data).matches("%%EOF").count(), 1, "{}", path);
+        }
+    }
+
+    fn write_sales_csv(name: &str, text: &str) -> String {
+        let path = std::env::temp_dir().join(format!("promo_fin_{}_{}.csv", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn sales_lines_count_the_header_and_quoted_line_breaks() {
+        let path = write_sales_csv(
+            "sales_lines",
+            "Ship Date,Customer,Order,Qty,Part,Description,Price\n\
+             2020-05-01,Acme,1001,6,AB-100,\"Test\npart\",2.50\n\
+             2020-05-02,Bolt,1002,six,AB-100,Test part,2.50\n",
+        );
+        let sales_lines = read_sales_lines(&path, &columns()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let acme: Vec<String> = ["2020-05-01", "Acme", "1001", "6", "AB-100", "Test\npart", "2.50"].iter().map(|cell| cell.to_string()).collect();
+        assert_eq!(sales_lines.get(&acme), Some(&2));
+        let bolt: Vec<String> = ["2020-05-02", "Bolt", "1002", "six", "AB-100", "Test part", "2.50"].iter().map(|cell| cell.to_string()).collect();
+        assert_eq!(sales_lines.get(&bolt), Some(&4));
+    }
+
+    #[test]
+    fn invalid_cells_name_their_sales_line() {
+        let mut run = promo_run(vec![
+            ("Bolt", promotion(vec![section(1, 1, 1, "AB-100", vec![sale("Bolt", "six", "AB-100", "2.50")])])),
+        ]);
+        let path = write_sales_csv(
+            "invalid_cell",
+            "Ship Date,Customer,Order,Qty,Part,Description,Price\n\
+             2020-05-01,Acme,1001,6,AB-100,Test part,2.50\n\
+             2020-05-01,Bolt,1001,six,AB-100,Test part,2.50\n",
+        );
+        run.sales_lines = read_sales_lines(&path, &run.columns).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut sinks: Vec<Box<dyn ReportSink>> = vec![Box::new(MemorySink::new())];
+        let reports = ReportSelection { customer_missing: false, ..ReportSelection::default() };
+        match write_customer_reports(&run, &mut sinks, &ArchiveLayout::default(), &LayoutConfig::default(), &reports) {
+            Err(PromoFinError::InvalidCell { customer, line, column, .. }) => {
+                assert_eq!(customer, "Bolt");
+                assert_eq!(line, Some(3));
+                assert_eq!(column, "Qty");
+            }
+            other => panic!("expected an invalid cell, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn json_model_lists_customers_in_order_with_caps_and_recommendations() {
+        let run = promo_run(vec![
+            ("Bolt", promotion(vec![section(0, 5, 2, "AB-100", vec![sale("Bolt", "2", "AB-100", "2.50")])])),
+            ("Acme", promotion(vec![section(3, 5, 17, "AB-100", vec![sale("Acme", "17", "AB-100", "2.50")])])),
+        ]);
+        let caps = QualificationCaps::from_json(&serde_json::json!({ "promo_sections": [{ "max_qualifications": 3 }] }));
+        let mut out = Vec::new();
+        write_missing_report_json(&run.data, &run.prices, &caps, &mut out).unwrap();
+        let model: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(
+            model,
+            serde_json::json!([
+                {
+                    "customer": "Acme",
+                    "sections": [{
+                        "promo": 1,
+                        "times_qualified": 3,
+                        "needed": [],
+                        "maximum_qualifications": 3,
+                        "maximum_reached": true,
+                    }],
+                },
+                {
+                    "customer": "Bolt",
+                    "sections": [{
+                        "promo": 1,
+                        "times_qualified": 0,
+                        "needed": [{
+                            "missing_part_numbers": [{
+                                "join_type": AndOrType::And.to_string(),
+                                "items": [{ "missing_part_numbers": ["AB-100"], "amount_needed": 3, "qty_per_tier": 5.0 }],
+                            }],
+                        }],
+                        "maximum_qualifications": 3,
+                        "maximum_reached": false,
+                        "recommended": {
+                            "purchases": [{ "part_number": "AB-100", "quantity": 3, "unit_price": 2.5 }],
+                            "total_cost": 7.5,
+                            "complete": true,
+                        },
+                    }],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn csv_has_a_row_per_needed_group_and_quotes_part_lists() {
+        let mut bolt = section(0, 5, 2, "AB-100", vec![sale("Bolt", "2", "AB-100", "2.50")]);
+        bolt["part"][0]["type_prod"][0]["part_numbers"] = serde_json::json!(["AB-100", "AB-200"]);
+        let run = promo_run(vec![
+            ("Bolt", promotion(vec![bolt])),
+            ("Acme", promotion(vec![section(3, 5, 17, "AB-100", vec![sale("Acme", "17", "AB-100", "2.50")])])),
+        ]);
+        let caps = QualificationCaps::from_json(&serde_json::json!({ "promo_sections": [{ "max_qualifications": 3 }] }));
+        let mut out = Vec::new();
+        write_missing_report_csv(&run.data, &run.prices, &caps, &mut out).unwrap();
+
+        let mut reader = csv::Reader::from_reader(out.as_slice());
+        assert_eq!(
+            reader.headers().unwrap().iter().collect::<Vec<_>>(),
+            vec!["Customer", "Promo", "Times Qualified", "Requirement", "Join Type", "Amount Needed", "Part Numbers", "Status", "Recommended"]
+        );
+        let rows: Vec<Vec<String>> =
+            reader.records().map(|record| record.unwrap().iter().map(str::to_owned).collect()).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0], vec!["Acme", "1", "3", "", "", "", "", "Maximum reached", ""]);
+        assert_eq!(&rows[1][..7], &["Bolt", "1", "0", "1", AndOrType::And.to_string().as_str(), "3", "AB-100, AB-200"]);
+        assert_eq!(rows[1][7], "");
+        assert_eq!(rows[1][8], "Recommended: 3 x AB-100 at $2.50 - estimated $7.50");
+        // the comma inside the part list must not split the field
+        assert!(String::from_utf8(out).unwrap().contains("\"AB-100, AB-200\""));
+    }
+
+    #[test]
+    fn text_report_stops_at_the_maximum() {
+        let mut run = promo_run(vec![
+            ("Acme", promotion(vec![section(3, 5, 17, "AB-100", vec![sale("Acme", "17", "AB-100", "2.50")])])),
+        ]);
+        let caps = QualificationCaps::from_json(&serde_json::json!({ "promo_sections": [{ "max_qualifications": 3 }] }));
+        let mut out = Vec::new();
+        display_missing_report(&mut run.data, &caps, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("Maximum reached"));
+        assert!(!text.contains("you need to purchase"));
+
+        let mut out = Vec::new();
+        display_missing_report(&mut run.data, &QualificationCaps::default(), &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("To get another you need to purchase:"));
+    }
+}
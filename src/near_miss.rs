@@ -0,0 +1,403 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use serde::{Deserialize, Serialize};
+use backfat::container_objects::text_box::{TextBox, TextAlignment};
+use backfat::container_objects::list_box::{TypeOfItem, ListBox, ListBoxBorder, RowData, RowDataTypes};
+use backfat::font::font_info::FontInfo;
+use backfat::font::font_sizes::Font;
+use promo_input::general::and_or::AndOrType;
+use crate::config::{LayoutConfig, TextStyle};
+use crate::error::PromoFinError;
+use crate::metadata::DocumentInfo;
+use crate::missing_report::{generate_missing_report_for_section, MissingPartNumber, NeededSections, PromoRun};
+use crate::pdf::{scale_columns, with_period, PdfReportBuilder};
+use crate::recommend::cheapest_path;
+
+/// What customers are ranked by in the near-miss report.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RankBy {
+    /// Units still to buy for the next qualification.
+    Units,
+    /// Estimated cost of the cheapest purchases to the next qualification.
+    Dollars,
+}
+
+impl Default for RankBy {
+    fn default() -> Self {
+        RankBy::Units
+    }
+}
+
+/// Which customer and promo sections make the near-miss report, and how they are ranked.
+///
+/// Customers are split between reps by `reps_csv` (columns `Customer` and `Rep`); customers
+/// it does not list are grouped under `Unassigned`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct NearMissOptions {
+    pub rank_by: RankBy,
+    /// Keeps sections whose remaining units are at most this percent of a full tier.
+    pub within_percent: Option<f64>,
+    /// Keeps sections whose remaining units or dollars, per `rank_by`, are at most this.
+    pub max_remaining: Option<f64>,
+    pub reps_csv: Option<String>,
+}
+
+const UNASSIGNED_REP: &str = "Unassigned";
+
+/// One customer's promo section and how far it is from qualifying again.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct NearMiss {
+    pub rep: String,
+    pub customer: String,
+    /// One based promo number, as printed in the reports.
+    pub promo: usize,
+    pub times_qualified: i64,
+    pub remaining_units: i64,
+    /// Cost of the cheapest purchases, `None` when no price is known.
+    pub remaining_dollars: Option<f64>,
+    /// Remaining units as a percent of the units of a full tier.
+    pub percent_remaining: f64,
+}
+
+/// Units left and units of a full tier for one requirement: the smallest alternative of an
+/// Or group, the `n` smallest of an Any(n) group, every item of an And group.
+fn requirement_units(join_type: &AndOrType, items: &[MissingPartNumber]) -> (i64, f64) {
+    let take = match join_type {
+        AndOrType::Or => 1,
+        AndOrType::Any(n) => *n as usize,
+        AndOrType::And | AndOrType::None => items.len(),
+    };
+    let mut smallest: Vec<&MissingPartNumber> = items.iter().collect();
+    smallest.sort_by_key(|item| item.amount_needed);
+    smallest
+        .into_iter()
+        .take(take)
+        .fold((0, 0.0), |(units, tier), item| (units + item.amount_needed, tier + item.qty_per_tier))
+}
+
+fn section_units(needed: &[NeededSections]) -> (i64, f64) {
+    needed
+        .iter()
+        .flat_map(|sec| sec.missing_part_numbers.iter())
+        .map(|(join_type, items)| requirement_units(join_type, items))
+        .fold((0, 0.0), |(units, tier), (u, t)| (units + u, tier + t))
+}
+
+fn read_reps(path: &str) -> Result<HashMap<String, String>, PromoFinError> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h.trim().eq_ignore_ascii_case(name));
+    let (customer, rep) = match (column("Customer"), column("Rep")) {
+        (Some(customer), Some(rep)) => (customer, rep),
+        _ => {
+            return Err(PromoFinError::Config {
+                path: path.to_owned(),
+                message: "reps csv needs Customer and Rep columns".to_owned(),
+            })
+        }
+    };
+    let mut reps = HashMap::new();
+    for record in reader.records() {
+        let record = record?;
+        if let (Some(name), Some(rep)) = (record.get(customer), record.get(rep)) {
+            if !rep.trim().is_empty() {
+                reps.insert(name.trim().to_owned(), rep.trim().to_owned());
+            }
+        }
+    }
+    Ok(reps)
+}
+
+impl NearMissOptions {
+    fn keeps(&self, near_miss: &NearMiss) -> bool {
+        if let Some(percent) = self.within_percent {
+            if near_miss.percent_remaining > percent {
+                return false;
+            }
+        }
+        match (self.max_remaining, self.rank_by) {
+            (Some(max), RankBy::Units) => near_miss.remaining_units as f64 <= max,
+            (Some(max), RankBy::Dollars) => near_miss.remaining_dollars.map_or(false, |dollars| dollars <= max),
+            (None, _) => true,
+        }
+    }
+
+    /// Closest first; in dollars, sections without a known price go last.
+    fn compare(&self, a: &NearMiss, b: &NearMiss) -> std::cmp::Ordering {
+        let by_metric = match self.rank_by {
+            RankBy::Units => a.remaining_units.cmp(&b.remaining_units),
+            RankBy::Dollars => match (a.remaining_dollars, b.remaining_dollars) {
+                (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => std::cmp::Ordering::Equal,
+            },
+        };
+        by_metric.then_with(|| a.customer.cmp(&b.customer)).then_with(|| a.promo.cmp(&b.promo))
+    }
+}
+
+/// Ranks every customer and promo section still short of its next qualification, closest
+/// first, grouped by rep in name order.
+///
+/// Sections at their qualification cap or with nothing left to buy are left out.
+pub fn near_miss_ranking(
+    promo_run: &PromoRun,
+    options: &NearMissOptions,
+) -> Result<BTreeMap<String, Vec<NearMiss>>, PromoFinError> {
+    let reps = match &options.reps_csv {
+        Some(path) => read_reps(path)?,
+        None => HashMap::new(),
+    };
+    let mut ranking: BTreeMap<String, Vec<NearMiss>> = BTreeMap::new();
+    for (customer, promotion) in &promo_run.data {
+        let rep = reps.get(customer.trim()).map_or(UNASSIGNED_REP, |rep| rep.as_str());
+        for (sec_id, section) in promotion.promo_sections.iter().enumerate() {
+            if promo_run.caps.reached(sec_id, section.times_section_qualified) {
+                continue;
+            }
+            let needed = generate_missing_report_for_section(section);
+            let (remaining_units, tier_units) = section_units(&needed);
+            if remaining_units <= 0 {
+                continue;
+            }
+            let near_miss = NearMiss {
+                rep: rep.to_owned(),
+                customer: customer.clone(),
+                promo: sec_id + 1,
+                times_qualified: section.times_section_qualified,
+                remaining_units,
+                remaining_dollars: cheapest_path(customer, &needed, &promo_run.prices).map(|r| r.total_cost),
+                percent_remaining: if tier_units > 0.0 { remaining_units as f64 / tier_units * 100.0 } else { 100.0 },
+            };
+            if options.keeps(&near_miss) {
+                ranking.entry(rep.to_owned()).or_insert_with(Vec::new).push(near_miss);
+            }
+        }
+    }
+    for near_misses in ranking.values_mut() {
+        near_misses.sort_by(|a, b| options.compare(a, b));
+    }
+    Ok(ranking)
+}
+
+fn dollars_text(dollars: Option<f64>) -> String {
+    dollars.map_or(String::new(), |dollars| format!("{:.2}", dollars))
+}
+
+/// Writes the ranking as csv, one row per customer and promo section, ranked within each rep.
+pub fn write_near_miss_csv<W: Write>(
+    ranking: &BTreeMap<String, Vec<NearMiss>>,
+    write_to: &mut W,
+) -> Result<(), PromoFinError> {
+    let mut writer = csv::Writer::from_writer(write_to);
+    writer.write_record(&[
+        "Rep",
+        "Rank",
+        "Customer",
+        "Promo",
+        "Times Qualified",
+        "Remaining Units",
+        "Remaining Dollars",
+        "Percent Of Tier Remaining",
+    ])?;
+    for (rep, near_misses) in ranking {
+        for (rank, near_miss) in near_misses.iter().enumerate() {
+            writer.write_record(&[
+                rep.as_str(),
+                (rank + 1).to_string().as_str(),
+                near_miss.customer.as_str(),
+                near_miss.promo.to_string().as_str(),
+                near_miss.times_qualified.to_string().as_str(),
+                near_miss.remaining_units.to_string().as_str(),
+                dollars_text(near_miss.remaining_dollars).as_str(),
+                format!("{:.1}", near_miss.percent_remaining).as_str(),
+            ])?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+const NEAR_MISS_COLUMN_WEIGHTS: [f64; 6] = [0.6, 3.0, 0.8, 1.2, 1.4, 1.2];
+const NEAR_MISS_TABLE_COLUMNS: std::ops::Range<usize> = 2..98;
+
+/// Writes the ranking as a PDF with each rep's list starting on a new page.
+pub fn write_near_miss_to_pdf<W: Write>(
+    ranking: &BTreeMap<String, Vec<NearMiss>>,
+    layout: &LayoutConfig,
+    write_to: &mut W,
+) -> Result<(), PromoFinError> {
+    let fonts = &layout.fonts;
+    let mut builder = PdfReportBuilder::new(&layout.missing_page, layout)?;
+    builder.enable_outline();
+    let border_color = builder.theme().secondary_color;
+    let period = &layout.decorations.promo_period;
+    builder.describe(DocumentInfo {
+        title: "Near Miss Report".to_owned(),
+        subject: with_period("Customers closest to their next promo, by rep".to_owned(), period),
+        keywords: vec!["Near Miss Report".to_owned(), period.clone()],
+    });
+
+    let col_size = scale_columns(&NEAR_MISS_COLUMN_WEIGHTS, NEAR_MISS_TABLE_COLUMNS.len());
+    let header = vec!["Rank", "Customer", "Promo", "Units", "Dollars", "% Left"]
+        .into_iter()
+        .map(|x| x.to_owned())
+        .collect::<Vec<String>>();
+
+    let mut new_page = false;
+    for (rep, near_misses) in ranking {
+        let mut txt = TextBox::new(format!("Near Misses for {}", rep), FontInfo::new(fonts.title, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
+        builder.text_row(1..99, new_page, 0.3, TextStyle::Title, &mut txt);
+        builder.start_customer(rep);
+        new_page = true;
+
+        let rows = near_misses
+            .iter()
+            .enumerate()
+            .map(|(rank, near_miss)| {
+                RowData::new(
+                    vec![
+                        (rank + 1).to_string(),
+                        near_miss.customer.clone(),
+                        near_miss.promo.to_string(),
+                        near_miss.remaining_units.to_string(),
+                        dollars_text(near_miss.remaining_dollars),
+                        format!("{:.1}", near_miss.percent_remaining),
+                    ],
+                    RowDataTypes::default(),
+                )
+            })
+            .collect::<Vec<RowData>>();
+        let header_row = RowData::new(header.clone(), RowDataTypes::default());
+        let item_alignments = vec![TextAlignment::RightJustifyBottom(0.05), TextAlignment::LeftJustifyBottom(0.05), TextAlignment::RightJustifyBottom(0.05), TextAlignment::RightJustifyBottom(0.05), TextAlignment::RightJustifyBottom(0.05), TextAlignment::RightJustifyBottom(0.05)];
+        let mut placement_handle = builder.manager.get_placement_handle(NEAR_MISS_TABLE_COLUMNS, false);
+        let mut list_box = ListBox::new(&rows, col_size.clone(), Some(&header_row), &mut builder.manager, FontInfo::new(fonts.small, Font::Helvetica), FontInfo::new(fonts.body, Font::Helvetica), ListBoxBorder::All(1.4, 1.4), None);
+        list_box.set_border_color(border_color);
+        list_box.header_has_border(false);
+        list_box.set_row_types(vec![TypeOfItem::String, TypeOfItem::String, TypeOfItem::String, TypeOfItem::String, TypeOfItem::String, TypeOfItem::String]);
+        list_box.set_item_column_alignments(item_alignments);
+        list_box.set_header_column_alignments(vec![TextAlignment::LeftJustifyCenter(0.05); header.len()]);
+        builder.use_styles(&[TextStyle::Small, TextStyle::Body]);
+        placement_handle.draw(&mut list_box, &mut builder.pdf_draw, &builder.borders);
+    }
+
+    builder.draw_groups(0.0, 5.0, |_| (1.0, 0.0, 0.0));
+
+    builder.finish(write_to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::test_fixtures::{capped, promo_run, promotion, sale, section};
+    use crate::tiers::QualificationCaps;
+
+    /// Acme is 3 of 5 units ($7.50) short, Bolt 1 of 10 ($10.00) and Cole 1 of 4 with no price.
+    fn three_customers() -> PromoRun {
+        promo_run(vec![
+            ("Acme", promotion(vec![section(0, 5, 2, "AB-100", vec![sale("Acme", "2", "AB-100", "2.50")])])),
+            ("Bolt", promotion(vec![section(0, 10, 9, "CD-7", vec![sale("Bolt", "9", "CD-7", "10.00")])])),
+            ("Cole", promotion(vec![section(0, 4, 3, "EF-1", vec![])])),
+        ])
+    }
+
+    fn ranked(run: &PromoRun, options: &NearMissOptions) -> Vec<(String, usize)> {
+        near_miss_ranking(run, options).unwrap()[UNASSIGNED_REP]
+            .iter()
+            .map(|near_miss| (near_miss.customer.clone(), near_miss.promo))
+            .collect()
+    }
+
+    fn customers(ranked: &[(String, usize)]) -> Vec<&str> {
+        ranked.iter().map(|(customer, _)| customer.as_str()).collect()
+    }
+
+    fn item(amount_needed: i64, qty_per_tier: f64) -> MissingPartNumber {
+        MissingPartNumber { missing_part_numbers: vec!["AB-100".to_owned()], amount_needed, qty_per_tier }
+    }
+
+    #[test]
+    fn reps_match_customer_names_with_stray_spaces() {
+        let path = std::env::temp_dir().join(format!("promo_fin_reps_{}.csv", std::process::id()));
+        std::fs::write(&path, "Customer,Rep\n Acme ,Jo\n").unwrap();
+        let run = promo_run(vec![
+            ("Acme ", promotion(vec![section(0, 5, 2, "AB-100", vec![sale("Acme ", "2", "AB-100", "2.50")])])),
+        ]);
+        let options = NearMissOptions { reps_csv: Some(path.to_string_lossy().into_owned()), ..NearMissOptions::default() };
+        let ranking = near_miss_ranking(&run, &options);
+        std::fs::remove_file(&path).unwrap();
+
+        let ranking = ranking.unwrap();
+        assert_eq!(ranking.keys().collect::<Vec<_>>(), vec!["Jo"]);
+        assert_eq!(ranking["Jo"][0].remaining_units, 3);
+    }
+
+    #[test]
+    fn any_groups_count_their_n_smallest_requirements() {
+        let items = vec![item(4, 6.0), item(1, 2.0), item(3, 5.0)];
+        assert_eq!(requirement_units(&AndOrType::Or, &items), (1, 2.0));
+        assert_eq!(requirement_units(&AndOrType::Any(2), &items), (4, 7.0));
+        assert_eq!(requirement_units(&AndOrType::Any(5), &items), (8, 13.0));
+        assert_eq!(requirement_units(&AndOrType::And, &items), (8, 13.0));
+        assert_eq!(requirement_units(&AndOrType::Or, &[]), (0, 0.0));
+    }
+
+    #[test]
+    fn units_rank_closest_first_and_dollars_put_unpriced_last() {
+        let run = three_customers();
+        assert_eq!(customers(&ranked(&run, &NearMissOptions::default())), vec!["Bolt", "Cole", "Acme"]);
+        let by_dollars = NearMissOptions { rank_by: RankBy::Dollars, ..NearMissOptions::default() };
+        assert_eq!(customers(&ranked(&run, &by_dollars)), vec!["Acme", "Bolt", "Cole"]);
+    }
+
+    #[test]
+    fn within_percent_keeps_sections_close_to_a_full_tier() {
+        let run = three_customers();
+        let options = NearMissOptions { within_percent: Some(30.0), ..NearMissOptions::default() };
+        assert_eq!(customers(&ranked(&run, &options)), vec!["Bolt", "Cole"]);
+        let options = NearMissOptions { within_percent: Some(15.0), ..NearMissOptions::default() };
+        assert_eq!(customers(&ranked(&run, &options)), vec!["Bolt"]);
+    }
+
+    #[test]
+    fn max_remaining_is_measured_in_the_ranked_unit() {
+        let run = three_customers();
+        let units = NearMissOptions { max_remaining: Some(1.0), ..NearMissOptions::default() };
+        assert_eq!(customers(&ranked(&run, &units)), vec!["Bolt", "Cole"]);
+        // sections without a price never fit under a dollar limit
+        let dollars = NearMissOptions { rank_by: RankBy::Dollars, max_remaining: Some(10.0), ..NearMissOptions::default() };
+        assert_eq!(customers(&ranked(&run, &dollars)), vec!["Acme", "Bolt"]);
+    }
+
+    #[test]
+    fn sections_at_their_cap_are_skipped() {
+        let run = promo_run(vec![(
+            "Acme",
+            promotion(vec![
+                section(1, 5, 7, "AB-100", vec![sale("Acme", "7", "AB-100", "2.50")]),
+                section(1, 5, 7, "CD-7", vec![sale("Acme", "7", "CD-7", "1.00")]),
+            ]),
+        )]);
+        let caps = QualificationCaps::from_json(&json!({ "promo_sections": [{ "max_qualifications": 1 }, {}] }));
+        let run = capped(run, caps);
+        assert_eq!(ranked(&run, &NearMissOptions::default()), vec![("Acme".to_owned(), 2)]);
+    }
+
+    #[test]
+    fn csv_has_a_ranked_row_per_section() {
+        let ranking = near_miss_ranking(&three_customers(), &NearMissOptions::default()).unwrap();
+        let mut csv = Vec::new();
+        write_near_miss_csv(&ranking, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "Rep,Rank,Customer,Promo,Times Qualified,Remaining Units,Remaining Dollars,Percent Of Tier Remaining\n\
+             Unassigned,1,Bolt,1,0,1,10.00,10.0\n\
+             Unassigned,2,Cole,1,0,1,,25.0\n\
+             Unassigned,3,Acme,1,0,3,7.50,60.0\n"
+        );
+    }
+}
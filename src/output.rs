@@ -0,0 +1,171 @@
+use std::cell::RefCell;
+use std::io::{Seek, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use zip::write::{FileOptions, ZipWriter};
+use crate::error::PromoFinError;
+
+/// Destination for the per-customer report files of a run.
+///
+/// Entry paths are `/` separated and relative, as produced by [`crate::archive::ArchiveLayout`].
+pub trait ReportSink {
+    fn write_entry(&mut self, path: &str, data: &[u8]) -> Result<(), PromoFinError>;
+    /// Flushes anything buffered; called once after the last entry.
+    fn finish(&mut self) -> Result<(), PromoFinError>;
+}
+
+/// Writes entries into a zip archive.
+pub struct ZipSink<W: Write + Seek> {
+    writer: ZipWriter<W>,
+}
+
+impl<W: Write + Seek> ZipSink<W> {
+    pub fn new(inner: W) -> Self {
+        Self { writer: ZipWriter::new(inner) }
+    }
+}
+
+impl ZipSink<std::fs::File> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, PromoFinError> {
+        let path = path.as_ref();
+        let file = std::fs::File::create(path)
+            .map_err(|source| PromoFinError::CreateFile { path: path.display().to_string(), source })?;
+        Ok(Self::new(file))
+    }
+}
+
+impl<W: Write + Seek> ReportSink for ZipSink<W> {
+    fn write_entry(&mut self, path: &str, data: &[u8]) -> Result<(), PromoFinError> {
+        self.writer.start_file(path, FileOptions::default())?;
+        self.writer.write_all(data)?;
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), PromoFinError> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Writes entries as files below a root directory, creating folders as needed.
+pub struct DirectorySink {
+    root: PathBuf,
+}
+
+impl DirectorySink {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl ReportSink for DirectorySink {
+    fn write_entry(&mut self, path: &str, data: &[u8]) -> Result<(), PromoFinError> {
+        let mut full_path = self.root.clone();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            full_path.push(component);
+        }
+        if let Some(parent) = full_path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|source| PromoFinError::CreateFile { path: parent.display().to_string(), source })?;
+        }
+        std::fs::write(&full_path, data)
+            .map_err(|source| PromoFinError::CreateFile { path: full_path.display().to_string(), source })
+    }
+    fn finish(&mut self) -> Result<(), PromoFinError> {
+        Ok(())
+    }
+}
+
+/// Keeps entries in memory in the order they were written.
+///
+/// Clones share the same entries, so a clone kept aside can read what a boxed sink received.
+#[derive(Default, Debug, Clone)]
+pub struct MemorySink {
+    entries: Rc<RefCell<Vec<(String, Vec<u8>)>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn entries(&self) -> Vec<(String, Vec<u8>)> {
+        self.entries.borrow().clone()
+    }
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        self.entries.borrow().iter().find(|e| e.0 == path).map(|e| e.1.clone())
+    }
+}
+
+impl ReportSink for MemorySink {
+    fn write_entry(&mut self, path: &str, data: &[u8]) -> Result<(), PromoFinError> {
+        self.entries.borrow_mut().push((path.to_owned(), data.to_vec()));
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), PromoFinError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    const ENTRIES: [(&str, &[u8]); 3] = [
+        ("Missing_Reports/Acme Missing Report.pdf", b"missing" as &[u8]),
+        ("Acme/Promo#0.pdf", b"first" as &[u8]),
+        ("Acme/nested/Promo#1.pdf", b"second" as &[u8]),
+    ];
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("promo_fin_{}_{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn directory_sink_creates_nested_folders() {
+        let root = temp_path("directory_sink");
+        let mut sink = DirectorySink::new(&root);
+        for (path, data) in ENTRIES.iter() {
+            sink.write_entry(path, data).unwrap();
+        }
+        sink.finish().unwrap();
+
+        for (path, data) in ENTRIES.iter() {
+            assert_eq!(std::fs::read(root.join(path)).unwrap(), *data, "{}", path);
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn zip_sink_finishes_a_readable_archive() {
+        let zip_path = temp_path("zip_sink.zip");
+        let mut sink = ZipSink::create(&zip_path).unwrap();
+        for (path, data) in ENTRIES.iter() {
+            sink.write_entry(path, data).unwrap();
+        }
+        sink.finish().unwrap();
+        drop(sink);
+
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(archive.len(), ENTRIES.len());
+        for (index, (path, data)) in ENTRIES.iter().enumerate() {
+            let mut entry = archive.by_index(index).unwrap();
+            assert_eq!(entry.name(), *path);
+            let mut read = Vec::new();
+            entry.read_to_end(&mut read).unwrap();
+            assert_eq!(read, *data, "{}", path);
+        }
+        std::fs::remove_file(&zip_path).unwrap();
+    }
+
+    #[test]
+    fn memory_sink_clones_share_entries() {
+        let memory = MemorySink::new();
+        let mut sinks: Vec<Box<dyn ReportSink>> = vec![Box::new(memory.clone())];
+        for (path, data) in ENTRIES.iter() {
+            sinks[0].write_entry(path, data).unwrap();
+        }
+        assert_eq!(memory.entries().len(), ENTRIES.len());
+        assert_eq!(memory.get("Acme/nested/Promo#1.pdf"), Some(b"second".to_vec()));
+        assert_eq!(memory.get("Acme/Promo#1.pdf"), None);
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use serde::{Deserialize, Serialize};
+use backfat::container_objects::text_box::{TextBox, TextAlignment};
+use backfat::container_objects::list_box::{TypeOfItem, ListBox, ListBoxBorder, RowData, RowDataTypes};
+use backfat::font::font_info::FontInfo;
+use backfat::font::font_sizes::Font;
+use promo_input::general::promo_json::PromoSection;
+use crate::config::{LayoutConfig, TextStyle};
+use crate::error::PromoFinError;
+use crate::metadata::DocumentInfo;
+use crate::missing_report::PromoRun;
+use crate::pdf::{parse_price, scale_columns, with_period, PdfReportBuilder};
+
+/// What one qualification of a promo section is worth.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reward {
+    /// A fixed credit per qualification.
+    Flat { amount: f64 },
+    /// A share of the section's qualifying sales, paid once the section qualifies. When a cap
+    /// cut the qualifications short, only the capped share of the sales is paid on.
+    PercentOfSales { percent: f64 },
+    /// Units of a part number given per qualification.
+    FreeGoods { part_number: String, quantity: i64 },
+}
+
+/// The reward of one promo section, numbered from one as in the reports.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SectionReward {
+    pub promo: usize,
+    pub reward: Reward,
+}
+
+/// Rewards per promo section; sections without one pay nothing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct PayoutRules {
+    pub sections: Vec<SectionReward>,
+}
+
+impl PayoutRules {
+    fn reward(&self, section_index: usize) -> Option<&Reward> {
+        self.sections.iter().find(|s| s.promo == section_index + 1).map(|s| &s.reward)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FreeGoods {
+    pub part_number: String,
+    pub quantity: i64,
+}
+
+/// What one customer earned from one promo section.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PayoutLine {
+    pub promo: usize,
+    pub times_qualified: i64,
+    pub reward: String,
+    /// Qty times `Sale Price` of the section's qualifying rows.
+    pub qualifying_sales: f64,
+    pub credit: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_goods: Option<FreeGoods>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CustomerPayout {
+    pub customer: String,
+    pub lines: Vec<PayoutLine>,
+    pub credit: f64,
+}
+
+/// Every customer's payout, sorted by customer name, with totals for accounting.
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct PayoutRegister {
+    pub customers: Vec<CustomerPayout>,
+    pub total_credit: f64,
+    /// Free goods owed across all customers, one entry per part number.
+    pub total_free_goods: Vec<FreeGoods>,
+}
+
+/// Qty times `Sale Price` of the section's qualifying rows, which must all be numbers.
+fn qualifying_sales(promo_run: &PromoRun, customer: &str, section_index: usize, section: &PromoSection) -> Result<f64, PromoFinError> {
+    let mut total = 0.0;
+    for cells in promo_run.detail_rows(section).iter().flatten() {
+        let invalid_cell = |column: &'static str, value: &String| PromoFinError::InvalidCell {
+            customer: customer.to_owned(),
+            section: section_index,
+            line: promo_run.sales_lines.get(cells).cloned(),
+            column,
+            value: value.clone(),
+        };
+        let qty = cells[3].trim().parse::<f64>().map_err(|_| invalid_cell("Qty", &cells[3]))?;
+        let price = parse_price(&cells[6]).ok_or_else(|| invalid_cell("Sale Price", &cells[6]))?;
+        total += qty * price;
+    }
+    Ok(total)
+}
+
+fn describe_reward(reward: &Reward) -> String {
+    match reward {
+        Reward::Flat { amount } => format!("${:.2} each", amount),
+        Reward::PercentOfSales { percent } => format!("{}% of sales", percent),
+        Reward::FreeGoods { part_number, quantity } => format!("{} x {} each", quantity, part_number),
+    }
+}
+
+fn payout_line(
+    promo_run: &PromoRun,
+    customer: &str,
+    section_index: usize,
+    section: &PromoSection,
+    reward: &Reward,
+) -> Result<PayoutLine, PromoFinError> {
+    let times = section.times_section_qualified;
+    let times_before_cap = promo_run.times_before_cap(customer, section_index);
+    let sales = qualifying_sales(promo_run, customer, section_index, section)?;
+    let (credit, free_goods) = match reward {
+        Reward::Flat { amount } => (amount * times as f64, None),
+        Reward::PercentOfSales { percent } => {
+            let paid_sales = if times_before_cap > times {
+                sales * times as f64 / times_before_cap as f64
+            } else {
+                sales
+            };
+            (paid_sales * percent / 100.0, None)
+        }
+        Reward::FreeGoods { part_number, quantity } => (
+            0.0,
+            Some(FreeGoods { part_number: part_number.clone(), quantity: quantity * times }),
+        ),
+    };
+    Ok(PayoutLine {
+        promo: section_index + 1,
+        times_qualified: times,
+        reward: describe_reward(reward),
+        qualifying_sales: sales,
+        credit,
+        free_goods,
+    })
+}
+
+/// Works out the payout of every qualified section that has a reward.
+///
+/// Qualification counts are taken after the promo's caps, so capped sections stop paying;
+/// percent rewards are prorated to the capped share of the sales.
+/// Customers without a payout are left out.
+pub fn payout_register(promo_run: &PromoRun, rules: &PayoutRules) -> Result<PayoutRegister, PromoFinError> {
+    let mut names: Vec<&String> = promo_run.data.keys().collect();
+    names.sort();
+    let mut register = PayoutRegister::default();
+    let mut free_goods: BTreeMap<String, i64> = BTreeMap::new();
+    for name in names {
+        let lines: Vec<PayoutLine> = promo_run.data[name]
+            .promo_sections
+            .iter()
+            .enumerate()
+            .filter(|(_, section)| section.times_section_qualified > 0)
+            .filter_map(|(index, section)| {
+                rules.reward(index).map(|reward| payout_line(promo_run, name, index, section, reward))
+            })
+            .collect::<Result<_, _>>()?;
+        if lines.is_empty() {
+            continue;
+        }
+        for goods in lines.iter().filter_map(|line| line.free_goods.as_ref()) {
+            *free_goods.entry(goods.part_number.clone()).or_insert(0) += goods.quantity;
+        }
+        let credit = lines.iter().map(|line| line.credit).sum();
+        register.total_credit += credit;
+        register.customers.push(CustomerPayout { customer: name.clone(), lines, credit });
+    }
+    register.total_free_goods = free_goods
+        .into_iter()
+        .map(|(part_number, quantity)| FreeGoods { part_number, quantity })
+        .collect();
+    Ok(register)
+}
+
+fn free_goods_text(goods: &Option<FreeGoods>) -> String {
+    goods.as_ref().map_or(String::new(), |g| format!("{} x {}", g.quantity, g.part_number))
+}
+
+/// Writes the register as csv with one row per customer and promo section.
+pub fn write_payout_register_csv<W: Write>(register: &PayoutRegister, write_to: &mut W) -> Result<(), PromoFinError> {
+    let mut writer = csv::Writer::from_writer(write_to);
+    writer.write_record(&[
+        "Customer",
+        "Promo",
+        "Times Qualified",
+        "Reward",
+        "Qualifying Sales",
+        "Credit",
+        "Free Goods Part Number",
+        "Free Goods Quantity",
+    ])?;
+    for customer in &register.customers {
+        for line in &customer.lines {
+            let (part_number, quantity) = match &line.free_goods {
+                Some(goods) => (goods.part_number.clone(), goods.quantity.to_string()),
+                None => (String::new(), String::new()),
+            };
+            writer.write_record(&[
+                customer.customer.as_str(),
+                line.promo.to_string().as_str(),
+                line.times_qualified.to_string().as_str(),
+                line.reward.as_str(),
+                format!("{:.2}", line.qualifying_sales).as_str(),
+                format!("{:.2}", line.credit).as_str(),
+                part_number.as_str(),
+                quantity.as_str(),
+            ])?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+const REGISTER_COLUMN_WEIGHTS: [f64; 5] = [0.8, 1.0, 2.0, 1.6, 1.6];
+const REGISTER_TABLE_COLUMNS: std::ops::Range<usize> = 2..98;
+
+/// Writes the register as a PDF with a table per customer and the overall totals at the end.
+pub fn write_payout_register_to_pdf<W: Write>(
+    register: &PayoutRegister,
+    layout: &LayoutConfig,
+    write_to: &mut W,
+) -> Result<(), PromoFinError> {
+    let fonts = &layout.fonts;
+    let mut builder = PdfReportBuilder::new(&layout.missing_page, layout)?;
+    builder.enable_outline();
+    let border_color = builder.theme().secondary_color;
+    let period = &layout.decorations.promo_period;
+    builder.describe(DocumentInfo {
+        title: "Payout Register".to_owned(),
+        subject: with_period("Promo rewards owed per customer".to_owned(), period),
+        keywords: vec!["Payout Register".to_owned(), period.clone()],
+    });
+
+    let col_size = scale_columns(&REGISTER_COLUMN_WEIGHTS, REGISTER_TABLE_COLUMNS.len());
+    let header = vec!["Promo", "Qualified", "Reward", "Credit", "Free Goods"]
+        .into_iter()
+        .map(|x| x.to_owned())
+        .collect::<Vec<String>>();
+
+    for customer in &register.customers {
+        let mut txt = TextBox::new(format!("For Customer: {}", customer.customer), FontInfo::new(fonts.heading, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
+        builder.text_row(1..99, false, 0.3, TextStyle::Heading, &mut txt);
+        builder.start_customer(&customer.customer);
+
+        let rows = customer
+            .lines
+            .iter()
+            .map(|line| {
+                RowData::new(
+                    vec![
+                        line.promo.to_string(),
+                        line.times_qualified.to_string(),
+                        line.reward.clone(),
+                        format!("{:.2}", line.credit),
+                        free_goods_text(&line.free_goods),
+                    ],
+                    RowDataTypes::default(),
+                )
+            })
+            .collect::<Vec<RowData>>();
+        let header_row = RowData::new(header.clone(), RowDataTypes::default());
+        let item_alignments = vec![TextAlignment::LeftJustifyBottom(0.05), TextAlignment::RightJustifyBottom(0.05), TextAlignment::LeftJustifyBottom(0.05), TextAlignment::RightJustifyBottom(0.05), TextAlignment::LeftJustifyBottom(0.05)];
+        let mut placement_handle = builder.manager.get_placement_handle(REGISTER_TABLE_COLUMNS, false);
+        let mut list_box = ListBox::new(&rows, col_size.clone(), Some(&header_row), &mut builder.manager, FontInfo::new(fonts.small, Font::Helvetica), FontInfo::new(fonts.body, Font::Helvetica), ListBoxBorder::All(1.4, 1.4), None);
+        list_box.set_border_color(border_color);
+        list_box.header_has_border(false);
+        list_box.set_row_types(vec![TypeOfItem::String, TypeOfItem::String, TypeOfItem::String, TypeOfItem::Currency(2), TypeOfItem::String]);
+        list_box.set_item_column_alignments(item_alignments);
+        list_box.set_header_column_alignments(vec![TextAlignment::LeftJustifyCenter(0.05); header.len()]);
+        builder.use_styles(&[TextStyle::Small, TextStyle::Body]);
+        placement_handle.draw(&mut list_box, &mut builder.pdf_draw, &builder.borders);
+
+        let mut total = TextBox::new(format!("Customer Credit: ${:.2}", customer.credit), FontInfo::new(fonts.body, Font::Helvetica), Some(TextAlignment::RightJustifyCenter(0.05)), None, None, None);
+        builder.text_row(REGISTER_TABLE_COLUMNS, false, 0.27, TextStyle::Body, &mut total);
+        builder.spacer(8..92, 0.25, TextStyle::Small);
+    }
+
+    let mut total = TextBox::new(format!("Total Credit: ${:.2}", register.total_credit), FontInfo::new(fonts.title, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
+    builder.text_row(1..99, false, 0.3, TextStyle::Title, &mut total);
+    // the totals are not about the last customer drawn
+    builder.end_customer();
+    for goods in &register.total_free_goods {
+        let mut txt = TextBox::new(format!("Free Goods: {} x {}", goods.quantity, goods.part_number), FontInfo::new(fonts.body, Font::Helvetica), Some(TextAlignment::LeftCenter), None, None, None);
+        builder.text_row(2..98, false, 0.25, TextStyle::Body, &mut txt);
+    }
+
+    builder.draw_groups(0.0, 5.0, |_| (1.0, 0.0, 0.0));
+
+    builder.finish(write_to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{capped, promo_run, promotion, sale, section};
+    use crate::tiers::QualificationCaps;
+
+    fn rules() -> PayoutRules {
+        PayoutRules {
+            sections: vec![
+                SectionReward { promo: 1, reward: Reward::Flat { amount: 50.0 } },
+                SectionReward { promo: 2, reward: Reward::PercentOfSales { percent: 10.0 } },
+                SectionReward {
+                    promo: 3,
+                    reward: Reward::FreeGoods { part_number: "FG-1".to_owned(), quantity: 2 },
+                },
+            ],
+        }
+    }
+
+    fn run() -> PromoRun {
+        promo_run(vec![
+            (
+                "Acme",
+                promotion(vec![
+                    section(2, 5, 10, "AB-100", vec![sale("Acme", "10", "AB-100", "3.00")]),
+                    section(1, 4, 4, "CD-7", vec![sale("Acme", "4", "CD-7", "$25.00")]),
+                    section(3, 1, 3, "EF-2", vec![sale("Acme", "3", "EF-2", "5.00")]),
+                ]),
+            ),
+            (
+                "Bolt",
+                promotion(vec![
+                    section(0, 5, 1, "AB-100", vec![sale("Bolt", "1", "AB-100", "3.00")]),
+                    section(0, 4, 0, "CD-7", vec![]),
+                    section(1, 1, 1, "EF-2", vec![sale("Bolt", "1", "EF-2", "5.00")]),
+                ]),
+            ),
+            ("Crane", promotion(vec![section(0, 5, 0, "AB-100", vec![])])),
+        ])
+    }
+
+    #[test]
+    fn register_totals_each_reward_type() {
+        let register = payout_register(&run(), &rules()).unwrap();
+
+        let customers: Vec<&str> = register.customers.iter().map(|c| c.customer.as_str()).collect();
+        assert_eq!(customers, vec!["Acme", "Bolt"]);
+
+        let acme = &register.customers[0];
+        let credits: Vec<f64> = acme.lines.iter().map(|line| line.credit).collect();
+        assert_eq!(credits, vec![100.0, 10.0, 0.0]);
+        assert_eq!(acme.lines[1].qualifying_sales, 100.0);
+        assert_eq!(acme.lines[2].free_goods, Some(FreeGoods { part_number: "FG-1".to_owned(), quantity: 6 }));
+        assert_eq!(acme.credit, 110.0);
+        assert_eq!(register.customers[1].credit, 0.0);
+
+        assert_eq!(register.total_credit, 110.0);
+        assert_eq!(register.total_free_goods, vec![FreeGoods { part_number: "FG-1".to_owned(), quantity: 8 }]);
+    }
+
+    #[test]
+    fn register_csv_has_a_row_per_paid_section() {
+        let mut out = Vec::new();
+        write_payout_register_csv(&payout_register(&run(), &rules()).unwrap(), &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            rows,
+            vec![
+                "Customer,Promo,Times Qualified,Reward,Qualifying Sales,Credit,Free Goods Part Number,Free Goods Quantity",
+                "Acme,1,2,$50.00 each,30.00,100.00,,",
+                "Acme,2,1,10% of sales,100.00,10.00,,",
+                "Acme,3,3,2 x FG-1 each,15.00,0.00,FG-1,6",
+                "Bolt,3,1,2 x FG-1 each,5.00,0.00,FG-1,2",
+            ]
+        );
+    }
+
+    #[test]
+    fn percent_rewards_are_prorated_to_the_cap() {
+        let run = promo_run(vec![(
+            "Acme",
+            promotion(vec![
+                section(0, 5, 0, "AB-100", vec![]),
+                section(2, 4, 8, "CD-7", vec![sale("Acme", "8", "CD-7", "25.00")]),
+            ]),
+        )]);
+        let caps = QualificationCaps::from_json(&serde_json::json!({ "promo_sections": [{}, { "max_qualifications": 1 }] }));
+        let register = payout_register(&capped(run, caps), &rules()).unwrap();
+        let line = &register.customers[0].lines[0];
+        assert_eq!(line.times_qualified, 1);
+        assert_eq!(line.qualifying_sales, 200.0);
+        assert_eq!(line.credit, 10.0);
+    }
+
+    #[test]
+    fn sales_that_are_not_numbers_are_reported() {
+        let mut run = promo_run(vec![(
+            "Acme",
+            promotion(vec![
+                section(0, 5, 0, "AB-100", vec![]),
+                section(1, 4, 4, "CD-7", vec![sale("Acme", "4", "CD-7", "n/a")]),
+            ]),
+        )]);
+        let cells = run.detail_rows(&run.data["Acme"].promo_sections[1])[0][0].clone();
+        run.sales_lines.insert(cells, 12);
+        match payout_register(&run, &rules()) {
+            Err(PromoFinError::InvalidCell { customer, section, line, column, value }) => {
+                assert_eq!((customer.as_str(), section, line), ("Acme", 1, Some(12)));
+                assert_eq!((column, value.as_str()), ("Sale Price", "n/a"));
+            }
+            other => panic!("expected an invalid cell, got {:?}", other),
+        }
+    }
+}
@@ -7,14 +7,40 @@ use backfat::container::rectangle::Border;
 use std::cell::RefCell;
 use backfat::font::font_sizes::{Font, create_font_recource_id};
 use backfat::container::manager::Manager;
-use lopdf::{Stream, Object};
+use lopdf::{Dictionary, Object, ObjectId, Stream, StringFormat};
 use lopdf::content::{Content, Operation};
 use lopdf::dictionary;
 use std::io::{Write};
+use std::collections::HashMap;
+use std::ops::Range;
 use backfat::container::container_trait::DrawInfoReq;
-
+use crate::config::{FontSizes, LayoutConfig, PageDecorations, PageLayout, RenderOptions, RunningText, SectionStyle, TextStyle};
+use crate::text::{encode_win_ansi, helvetica_width, pdf_text_string, text_operations, HELVETICA_RESOURCE};
+use crate::theme::{load_logo, LogoImage, Rgb, Theme};
+use crate::encryption::{encrypt_document, DocumentPasswords, EncryptionOptions};
+use crate::error::PromoFinError;
+use crate::fonts::{helvetica_resource_names, remove_font_resources, FontSet};
+use crate::metadata::{document_id, info_dictionary, xmp_stream, DocumentInfo, DocumentMetadata, Timestamp};
+use crate::pdfa::{add_output_intent, check_pdfa, load_icc_profile, ArchivalOptions, PDFA_VERSION, PDFA_XMP_SCHEMA};
+
+#[derive(Default)]
 pub struct PdfDrawInfo {
     pub pdf: Vec<Vec<Operation>>,
+    /// Resource of the embedded face of each style that has one.
+    style_resources: HashMap<TextStyle, Vec<u8>>,
+    /// Styles of the container being drawn, with their font sizes.
+    drawing: Vec<(f64, TextStyle)>,
+}
+
+impl PdfDrawInfo {
+    /// The style of a `Tf` at `font_size`: a container's only style, or for a list box whose
+    /// header and items differ the one drawn at that size.
+    fn style_of(&self, font_size: f64) -> Option<TextStyle> {
+        match self.drawing.as_slice() {
+            [(_, style)] => Some(*style),
+            drawing => drawing.iter().find(|d| (d.0 - font_size).abs() < 0.01).map(|d| d.1),
+        }
+    }
 }
 impl DrawInfoReq for PdfDrawInfo {
     fn increment_page_buffer(&mut self, page_number: usize) {
@@ -27,41 +53,685 @@ impl DrawInfoReq for PdfDrawInfo {
         self.pdf.len()
     }
 
-    fn insert_into_page(&mut self, page_num: usize, operation: Operation) {
+    fn insert_into_page(&mut self, page_num: usize, mut operation: Operation) {
+        if operation.operator == "Tf" && operation.operands.len() == 2 {
+            let font_size = match &operation.operands[1] {
+                Object::Integer(size) => *size as f64,
+                Object::Real(size) => *size as f64,
+                _ => 0.0,
+            };
+            if let Some(resource) = self.style_of(font_size).and_then(|style| self.style_resources.get(&style)) {
+                operation.operands[0] = Object::Name(resource.clone());
+            }
+        }
         self.pdf[page_num].push(operation);
     }
 }
 
+/// Relative widths of the detail table columns, scaled to the grid columns the table spans.
+const DETAIL_COLUMN_WEIGHTS: [f64; 7] = [10.0, 20.0, 12.0, 4.0, 11.0, 25.0, 9.0];
+/// Grid columns the detail table spans, leaving two on each side.
+const DETAIL_TABLE_COLUMNS: Range<usize> = 2..98;
+
+/// Splits `total` grid columns in proportion to `weights`, giving every column at least one and
+/// handing the remainder to the largest fractions so the sizes add up to `total`.
+pub fn scale_columns(weights: &[f64], total: usize) -> Vec<usize> {
+    let total = total.max(weights.len());
+    let weight_sum: f64 = weights.iter().sum();
+    let exact: Vec<f64> = weights.iter().map(|w| w / weight_sum * total as f64).collect();
+    let mut sizes: Vec<usize> = exact.iter().map(|e| (e.floor() as usize).max(1)).collect();
+    let mut by_fraction: Vec<usize> = (0..weights.len()).collect();
+    by_fraction.sort_by(|a, b| {
+        let fraction = |i: usize| exact[i] - exact[i].floor();
+        fraction(*b).partial_cmp(&fraction(*a)).unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let mut assigned: usize = sizes.iter().sum();
+    let mut next = 0;
+    while assigned < total {
+        sizes[by_fraction[next % by_fraction.len()]] += 1;
+        assigned += 1;
+        next += 1;
+    }
+    // only reached when raising tiny weights to one column overshot
+    while assigned > total {
+        let largest = (0..sizes.len()).max_by_key(|i| sizes[*i]).unwrap_or(0);
+        sizes[largest] -= 1;
+        assigned -= 1;
+    }
+    sizes
+}
+
+/// Parses a sale price cell, allowing a leading `$` and thousands separators.
+pub fn parse_price(value: &str) -> Option<f64> {
+    value.trim().trim_start_matches('$').replace(',', "").parse::<f64>().ok()
+}
+
+/// Page setup, drawing state and document assembly shared by every PDF report.
+///
+/// Reports place their containers through `manager` into `pdf_draw`, then call
+/// [`PdfReportBuilder::finish`] to build the lopdf document and save it.
+pub struct PdfReportBuilder {
+    pub manager: Manager,
+    pub pdf_draw: PdfDrawInfo,
+    pub borders: Option<RefCell<Vec<Border>>>,
+    dpi: f64,
+    page: PageLayout,
+    decorations: PageDecorations,
+    theme: Theme,
+    render: RenderOptions,
+    metadata: DocumentMetadata,
+    info: DocumentInfo,
+    archival: ArchivalOptions,
+    /// Output intent profile, loaded when archival output is enabled.
+    icc_profile: Option<Vec<u8>>,
+    encryption: EncryptionOptions,
+    passwords: Option<DocumentPasswords>,
+    /// Decoded theme logo, moved into the document when it is built.
+    logo: Option<LogoImage>,
+    /// Embedded faces replacing Helvetica, empty when the layout names none.
+    fonts: FontSet,
+    font_sizes: FontSizes,
+    /// First page of each customer or section, used to fill the running header and footer.
+    page_contexts: Vec<(usize, PageContext)>,
+    outline: bool,
+}
+
+#[derive(Clone, Default, PartialEq)]
+struct PageContext {
+    customer: String,
+    section: String,
+    /// False for pages about no customer, which get no bookmark.
+    outlined: bool,
+}
+
+/// Height of the band reserved inside the margin for a running header or footer.
+const DECORATION_BAND_INCH: f64 = 0.3;
+/// Distance of left and right aligned header and footer text from the page edge.
+const DECORATION_SIDE_INCH: f64 = 0.25;
+/// Space between a card's edge and the group it holds, in pixels.
+const CARD_PADDING: f64 = 4.0;
+const CARD_BORDER_WIDTH: f64 = 1.0;
+/// Resource name of the theme logo image.
+const LOGO_RESOURCE: &str = "PFLogo";
+
+impl PdfReportBuilder {
+    /// Sets up `page` with the decorations, theme and font faces of `layout`, loading the logo
+    /// and font files it names.
+    pub fn new(page: &PageLayout, layout: &LayoutConfig) -> Result<Self, PromoFinError> {
+        let decorations = &layout.decorations;
+        let theme = &layout.theme;
+        let band = |text: &Option<RunningText>| if text.is_some() { DECORATION_BAND_INCH } else { 0.0 };
+        let brand_bar = if theme.has_brand_bar() { theme.bar_height_inch } else { 0.0 };
+        let logo = match &theme.logo {
+            Some(path) => Some(load_logo(path)?),
+            None => None,
+        };
+        let icc_profile = if layout.archival.enabled {
+            if layout.font_faces.default.is_none() {
+                return Err(PromoFinError::Archival {
+                    problems: vec!["layout.font_faces.default must be set so every font is embedded".to_owned()],
+                });
+            }
+            Some(load_icc_profile(&layout.archival)?)
+        } else {
+            None
+        };
+        let fonts = FontSet::load(&layout.font_faces)?;
+        let pdf_draw = PdfDrawInfo { style_resources: fonts.style_resources(), ..PdfDrawInfo::default() };
+        Ok(Self {
+            manager: Manager::new(
+                page.width_inch(),
+                page.height_inch(),
+                page.dpi,
+                page.margin_top + brand_bar + band(&decorations.header),
+                page.margin_bottom + band(&decorations.footer),
+            ),
+            pdf_draw,
+            borders: Some(RefCell::new(Vec::new())),
+            dpi: page.dpi,
+            page: page.clone(),
+            decorations: decorations.clone(),
+            theme: theme.clone(),
+            render: layout.render.clone(),
+            metadata: layout.metadata.clone(),
+            info: DocumentInfo::default(),
+            archival: layout.archival.clone(),
+            icc_profile,
+            encryption: layout.encryption.clone(),
+            passwords: None,
+            logo,
+            fonts,
+            font_sizes: layout.fonts.clone(),
+            page_contexts: Vec::new(),
+            outline: false,
+        })
+    }
+
+    /// Adds a bookmark per customer, with nested bookmarks per section, to the finished document.
+    pub fn enable_outline(&mut self) {
+        self.outline = true;
+    }
+
+    /// Encrypts the finished document with `customer`'s user password when encryption is enabled.
+    pub fn protect_for(&mut self, customer: &str) -> Result<(), PromoFinError> {
+        if !self.encryption.enabled {
+            return Ok(());
+        }
+        if self.archival.enabled {
+            return Err(PromoFinError::Archival { problems: vec!["PDF/A documents cannot be encrypted".to_owned()] });
+        }
+        self.passwords = Some(self.encryption.passwords(customer)?);
+        Ok(())
+    }
+
+    /// Sets the title, subject and keywords written to the document information.
+    pub fn describe(&mut self, info: DocumentInfo) {
+        self.info = info;
+    }
+
+    /// Marks the page holding the last placed row as where `customer`'s pages begin.
+    pub fn start_customer(&mut self, customer: &str) {
+        let context = PageContext { customer: customer.to_owned(), section: String::new(), outlined: true };
+        self.page_contexts.push((self.manager.get_page_cnt(), context));
+    }
+
+    /// Marks the page holding the last placed row as where pages about no single customer
+    /// begin, such as totals: the header and footer placeholders go blank and no bookmark is added.
+    pub fn end_customer(&mut self) {
+        self.page_contexts.push((self.manager.get_page_cnt(), PageContext::default()));
+    }
+
+    /// Marks the page holding the last placed row as where `section` of the current customer begins.
+    pub fn start_section(&mut self, section: &str) {
+        let customer = self.page_contexts.last().map(|c| c.1.customer.clone()).unwrap_or_default();
+        let context = PageContext { customer, section: section.to_owned(), outlined: true };
+        self.page_contexts.push((self.manager.get_page_cnt(), context));
+    }
+
+    pub fn dpi(&self) -> f64 {
+        self.dpi
+    }
+
+    pub fn theme(&self) -> &Theme {
+        &self.theme
+    }
+
+    /// Shows the text of the containers drawn next in the faces of `styles`: a text box's style,
+    /// or a list box's item style followed by its header style.
+    pub fn use_styles(&mut self, styles: &[TextStyle]) {
+        self.pdf_draw.drawing = styles.iter().map(|style| (self.font_sizes.size(*style), *style)).collect();
+    }
+
+    /// Height of the brand bar below the top margin, zero when the theme has no logo or title.
+    fn brand_bar_inch(&self) -> f64 {
+        if self.theme.has_brand_bar() { self.theme.bar_height_inch } else { 0.0 }
+    }
+
+    /// Draws a text box in `style` on a new row `height_inch` tall spanning `columns` of the
+    /// placement grid.
+    pub fn text_row(&mut self, columns: Range<usize>, new_page: bool, height_inch: f64, style: TextStyle, text_box: &mut TextBox) {
+        self.use_styles(&[style]);
+        let mut placement_handle = self.manager.get_placement_handle(columns, new_page);
+        placement_handle.set_pixel_height(height_inch * self.dpi);
+        placement_handle.draw(text_box, &mut self.pdf_draw, &self.borders);
+    }
+
+    /// Leaves an empty row `height_inch` tall.
+    pub fn spacer(&mut self, columns: Range<usize>, height_inch: f64, style: TextStyle) {
+        let mut space = TextBox::new("", FontInfo::new(self.font_sizes.size(style), Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
+        self.text_row(columns, false, height_inch, style, &mut space);
+    }
+
+    /// Decorates the placement groups as the render options ask: outlines in `debug_color` when
+    /// debugging the layout, otherwise cards in the theme colours or nothing at all.
+    pub fn draw_groups<F: Fn(usize) -> Rgb>(&mut self, grow: f64, debug_line_width: f64, debug_color: F) {
+        if self.render.debug_layout {
+            self.draw_group_outlines(grow, debug_line_width, debug_color);
+        } else if self.render.section_style == SectionStyle::Card {
+            self.draw_group_cards(grow + CARD_PADDING);
+        }
+    }
+
+    /// Every placement group's rectangle on each page it spans, grown by `grow` pixels on all sides.
+    fn group_placements(&self, grow: f64) -> Vec<(usize, PlacementInfo)> {
+        let mut placements = Vec::new();
+        for group_rec in self.manager.get_groups() {
+            for page_index in 0..group_rec.1.len() {
+
+                let mut pl: PlacementInfo = PlacementInfo::default();
+                pl.page_number = page_index;
+                pl.rec = group_rec.1[page_index].clone();
+                pl.page_size_info = self.manager.get_page_info();
+                pl.rec.x -= grow;
+                pl.rec.width += 2.0 * grow;
+                pl.rec.y -= grow;
+                pl.rec.height += 2.0 * grow;
+                placements.push((group_rec.0, pl));
+            }
+        }
+        placements
+    }
+
+    /// Outlines every placement group on each page it spans, grown by `grow` pixels on all sides.
+    pub fn draw_group_outlines<F: Fn(usize) -> Rgb>(&mut self, grow: f64, line_width: f64, color: F) {
+        for (group, pl) in self.group_placements(grow) {
+            draw_rectangle(&mut self.pdf_draw,
+                           &pl,
+                           line_width,
+                           color(group));
+        }
+    }
+
+    /// Puts every placement group on a tinted card with a thin border in the secondary colour.
+    fn draw_group_cards(&mut self, grow: f64) {
+        let (fill, border) = (self.theme.card_color, self.theme.secondary_color);
+        for (_, pl) in self.group_placements(grow) {
+            // draw_rectangle only strokes, so trace the card with it and fill that path instead
+            let mut scratch = PdfDrawInfo::default();
+            draw_rectangle(&mut scratch, &pl, CARD_BORDER_WIDTH, fill);
+            let mut background = vec![Operation::new("q", vec![])];
+            for operation in scratch.pdf.into_iter().nth(pl.page_number).unwrap_or_default() {
+                let operator = operation.operator.clone();
+                match operator.as_str() {
+                    "RG" => background.push(Operation::new("rg", operation.operands)),
+                    "S" | "s" => background.push(Operation::new("f", vec![])),
+                    _ => background.push(operation),
+                }
+            }
+            background.push(Operation::new("Q", vec![]));
+
+            self.pdf_draw.increment_page_buffer(pl.page_number);
+            let page = &mut self.pdf_draw.pdf[pl.page_number];
+            page.splice(0..0, background);
+            draw_rectangle(&mut self.pdf_draw, &pl, CARD_BORDER_WIDTH, border);
+        }
+    }
+
+    /// Draws the collected container borders and builds one pdf page per manager page.
+    pub fn build_document(&mut self) -> Result<lopdf::Document, PromoFinError> {
+        if let Some(brd) = self.borders.take() {
+            for border in brd.into_inner().into_iter() {
+
+                draw_rectangle(&mut self.pdf_draw,
+                               &border.rec,
+                               border.pixel_size,
+                               border.color);
+            };
+        }
+
+        let mut doc = lopdf::Document::with_version(if self.archival.enabled { PDFA_VERSION } else { "1.5" });
+        let pages_id = doc.new_object_id();
+
+        let resources_id = create_font_recource_id(&mut doc);
+        let mut v:Vec<lopdf::Object> = Vec::new();
+        let mut page_ids: Vec<ObjectId> = Vec::new();
+
+        let total_pages = self.manager.get_page_cnt() + 1;
+        let has_decorations = self.decorations.header.is_some() || self.decorations.footer.is_some();
+        if has_decorations || !self.theme.title.is_empty() {
+            let helvetica_id = doc.add_object(dictionary! {
+                "Type" => "Font",
+                "Subtype" => "Type1",
+                "BaseFont" => "Helvetica",
+                "Encoding" => "WinAnsiEncoding",
+            });
+            add_resource(&mut doc, resources_id, b"Font", HELVETICA_RESOURCE, helvetica_id)?;
+        }
+        let mut logo_size = None;
+        if let Some(mut logo) = self.logo.take() {
+            if let Some(soft_mask) = logo.soft_mask.take() {
+                let soft_mask_id = doc.add_object(soft_mask);
+                logo.image.dict.set("SMask", soft_mask_id);
+            }
+            let logo_id = doc.add_object(logo.image);
+            add_resource(&mut doc, resources_id, b"XObject", LOGO_RESOURCE, logo_id)?;
+            logo_size = Some((logo.width, logo.height));
+        }
+        for page in 0..total_pages {
+            let mut operations = Vec::new();
+            if self.theme.has_brand_bar() {
+                operations.extend(self.brand_operations(logo_size));
+            }
+            if has_decorations {
+                operations.extend(self.decoration_operations(page, total_pages));
+            }
+            self.pdf_draw.increment_page_buffer(page);
+            self.pdf_draw.pdf[page].extend(operations);
+        }
+        if !self.fonts.is_empty() {
+            let helvetica_names = helvetica_resource_names(&doc, resources_id)?;
+            for operations in self.pdf_draw.pdf.iter_mut() {
+                self.fonts.embed_operations(operations, &helvetica_names);
+            }
+            self.fonts.add_to_document(&mut doc, resources_id)?;
+            if !self.archival.enabled && self.fonts.needs_pdf_1_6() {
+                doc.version = "1.6".to_owned();
+            }
+            if self.archival.enabled {
+                // the default face stands in for every style without a face, so Helvetica is no longer shown anywhere
+                remove_font_resources(&mut doc, resources_id, &helvetica_names)?;
+            }
+        }
+
+        for page in 0..total_pages {
+            let content = Content {
+                operations: self.pdf_draw.pdf[page].clone()
+            };
+            let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode()?));
+            let page_id = doc.add_object(dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "Contents" => content_id,
+                });
+            v.push( page_id.into() );
+            page_ids.push(page_id);
+        };
+        let page_count = v.len() as i32;
+
+        let pages = dictionary! {
+            "Type" => "Pages",
+            "Kids" => v,
+            "Count" => page_count,
+            "Resources" => resources_id,
+            "MediaBox" => vec![0.into(), 0.into(), (self.manager.get_page_pixel_dims().0).into(), (self.manager.get_page_pixel_dims().1).into()],
+        };
+        doc.objects.insert(pages_id, Object::Dictionary(pages));
+        let mut catalog = dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        };
+        if self.outline {
+            if let Some(outlines_id) = self.add_outline(&mut doc, &page_ids) {
+                catalog.set("Outlines", outlines_id);
+                catalog.set("PageMode", "UseOutlines");
+            }
+        }
+        let created = Timestamp::now();
+        if self.metadata.xmp || self.archival.enabled {
+            let schemas = if self.archival.enabled { PDFA_XMP_SCHEMA } else { "" };
+            let metadata_id = doc.add_object(xmp_stream(&self.metadata, &self.info, &created, schemas));
+            catalog.set("Metadata", metadata_id);
+        }
+        if let Some(icc_profile) = self.icc_profile.take() {
+            let intent_id = add_output_intent(&mut doc, &self.archival, icc_profile);
+            catalog.set("OutputIntents", vec![intent_id.into()]);
+        }
+        let catalog_id = doc.add_object(catalog);
+        doc.trailer.set("Root", catalog_id);
+        let info_id = doc.add_object(info_dictionary(&self.metadata, &self.info, &created));
+        doc.trailer.set("Info", info_id);
+        let id = document_id(&self.info, &created);
+        doc.trailer.set("ID", vec![
+            Object::String(id.clone(), StringFormat::Hexadecimal),
+            Object::String(id, StringFormat::Hexadecimal),
+        ]);
+        doc.compress();
+        if self.archival.enabled {
+            let problems = check_pdfa(&doc);
+            if !problems.is_empty() {
+                return Err(PromoFinError::Archival { problems });
+            }
+        }
+        Ok(doc)
+    }
+
+    /// Writes the outline tree from the recorded customer and section starts, returning its root.
+    fn add_outline(&self, doc: &mut lopdf::Document, page_ids: &[ObjectId]) -> Option<ObjectId> {
+        let mut customers: Vec<(String, usize, Vec<(String, usize)>)> = Vec::new();
+        for (page, context) in self.page_contexts.iter().filter(|(_, context)| context.outlined) {
+            if context.section.is_empty() {
+                customers.push((context.customer.clone(), *page, Vec::new()));
+            } else if let Some(customer) = customers.last_mut() {
+                customer.2.push((context.section.clone(), *page));
+            }
+        }
+        if customers.is_empty() {
+            return None;
+        }
+
+        let root_id = doc.new_object_id();
+        let customer_ids: Vec<ObjectId> = customers.iter().map(|_| doc.new_object_id()).collect();
+        for (index, (title, page, sections)) in customers.iter().enumerate() {
+            let section_ids: Vec<ObjectId> = sections.iter().map(|_| doc.new_object_id()).collect();
+            for (section_index, (section_title, section_page)) in sections.iter().enumerate() {
+                let item = outline_item(section_title, page_ids[*section_page], customer_ids[index], &section_ids, section_index);
+                doc.objects.insert(section_ids[section_index], Object::Dictionary(item));
+            }
+            let mut item = outline_item(title, page_ids[*page], root_id, &customer_ids, index);
+            if let (Some(first), Some(last)) = (section_ids.first(), section_ids.last()) {
+                item.set("First", *first);
+                item.set("Last", *last);
+                // negative count: sections start collapsed under their customer
+                item.set("Count", -(section_ids.len() as i64));
+            }
+            doc.objects.insert(customer_ids[index], Object::Dictionary(item));
+        }
+
+        doc.objects.insert(root_id, Object::Dictionary(dictionary! {
+            "Type" => "Outlines",
+            "First" => customer_ids[0],
+            "Last" => customer_ids[customer_ids.len() - 1],
+            "Count" => customer_ids.len() as i64,
+        }));
+        Some(root_id)
+    }
+
+    /// Header and footer text for one page with its placeholders filled in.
+    fn decoration_operations(&self, page: usize, total_pages: usize) -> Vec<Operation> {
+        let context = self
+            .page_contexts
+            .iter()
+            .rev()
+            .find(|c| c.0 <= page)
+            .map(|c| c.1.clone())
+            .unwrap_or_default();
+
+        let customer_start = self
+            .page_contexts
+            .iter()
+            .filter(|c| c.0 <= page && c.1.customer == context.customer)
+            .map(|c| c.0)
+            .min()
+            .unwrap_or(0);
+        let customer_end = self
+            .page_contexts
+            .iter()
+            .find(|c| c.0 > page && c.1.customer != context.customer)
+            .map(|c| c.0)
+            .unwrap_or(total_pages);
+
+        let fill = |template: &str| {
+            template
+                .replace("{customer}", &context.customer)
+                .replace("{section}", &context.section)
+                .replace("{period}", &self.decorations.promo_period)
+                .replace("{pages}", &total_pages.to_string())
+                .replace("{page}", &(page + 1).to_string())
+                .replace("{customer_pages}", &(customer_end - customer_start).to_string())
+                .replace("{customer_page}", &(page - customer_start + 1).to_string())
+        };
+
+        let (page_width, page_height) = self.manager.get_page_pixel_dims();
+        let size = self.decorations.font_size;
+        let side = DECORATION_SIDE_INCH * self.dpi;
+        let mut operations = Vec::new();
+        let bands = [
+            (&self.decorations.header, page_height - (self.page.margin_top + self.brand_bar_inch() + DECORATION_BAND_INCH * 0.65) * self.dpi),
+            (&self.decorations.footer, (self.page.margin_bottom + DECORATION_BAND_INCH * 0.35) * self.dpi),
+        ];
+        for (text, baseline) in bands.iter() {
+            let text = match text {
+                Some(text) => text,
+                None => continue,
+            };
+            for (template, align) in [(&text.left, 0), (&text.center, 1), (&text.right, 2)].iter() {
+                let (encoded, width) = self.line_text(&fill(template), size);
+                if encoded.is_empty() {
+                    continue;
+                }
+                let x = match align {
+                    0 => side,
+                    1 => (page_width - width) / 2.0,
+                    _ => page_width - side - width,
+                };
+                operations.extend(text_operations(HELVETICA_RESOURCE, size, x, *baseline, encoded));
+            }
+        }
+        operations
+    }
+
+    /// Bytes and width of one line drawn with the Helvetica resource. Text that the default face
+    /// will replace is kept as UTF-8 for [`FontSet::embed_operations`] to re-encode; it is shown
+    /// with Helvetica advances either way.
+    fn line_text(&self, text: &str, font_size: f64) -> (Vec<u8>, f64) {
+        let encoded = encode_win_ansi(text);
+        let width = helvetica_width(&encoded, font_size);
+        if self.fonts.has_default() {
+            (text.as_bytes().to_vec(), width)
+        } else {
+            (encoded, width)
+        }
+    }
+
+    /// Logo, title and a rule in the brand bar at the top of a page.
+    fn brand_operations(&self, logo_size: Option<(u32, u32)>) -> Vec<Operation> {
+        let (page_width, page_height) = self.manager.get_page_pixel_dims();
+        let bar = self.theme.bar_height_inch * self.dpi;
+        let top = page_height - self.page.margin_top * self.dpi;
+        let side = DECORATION_SIDE_INCH * self.dpi;
+        let mut operations = Vec::new();
+
+        let mut title_x = side;
+        if let Some((width, height)) = logo_size {
+            let draw_height = bar * 0.8;
+            let draw_width = draw_height * width as f64 / height.max(1) as f64;
+            operations.push(Operation::new("q", vec![]));
+            operations.push(Operation::new("cm", vec![
+                draw_width.into(), 0.into(), 0.into(), draw_height.into(), side.into(), (top - bar * 0.9).into(),
+            ]));
+            operations.push(Operation::new("Do", vec![LOGO_RESOURCE.into()]));
+            operations.push(Operation::new("Q", vec![]));
+            title_x = side + draw_width + 0.15 * self.dpi;
+        }
+
+        let size = self.theme.title_font_size;
+        let (encoded, _) = self.line_text(&self.theme.title, size);
+        if !encoded.is_empty() {
+            let (r, g, b) = self.theme.primary_color;
+            operations.push(Operation::new("q", vec![]));
+            operations.push(Operation::new("rg", vec![r.into(), g.into(), b.into()]));
+            operations.extend(text_operations(HELVETICA_RESOURCE, size, title_x, top - bar / 2.0 - size * 0.35, encoded));
+            operations.push(Operation::new("Q", vec![]));
+        }
+
+        let (r, g, b) = self.theme.secondary_color;
+        operations.push(Operation::new("q", vec![]));
+        operations.push(Operation::new("RG", vec![r.into(), g.into(), b.into()]));
+        operations.push(Operation::new("w", vec![1.into()]));
+        operations.push(Operation::new("m", vec![side.into(), (top - bar).into()]));
+        operations.push(Operation::new("l", vec![(page_width - side).into(), (top - bar).into()]));
+        operations.push(Operation::new("S", vec![]));
+        operations.push(Operation::new("Q", vec![]));
+        operations
+    }
+
+    /// Builds the document, encrypts it when [`PdfReportBuilder::protect_for`] set a password,
+    /// and saves it to `write_to`.
+    pub fn finish<W: Write>(mut self, write_to: &mut W) -> Result<(), PromoFinError> {
+        let mut doc = self.build_document()?;
+        if let Some(passwords) = &self.passwords {
+            encrypt_document(&mut doc, &self.encryption, passwords)?;
+        }
+        doc.save_to(write_to)?;
+        Ok(())
+    }
+}
+
+/// Appends the promotion period to a document subject when one is configured.
+pub fn with_period(subject: String, period: &str) -> String {
+    if period.is_empty() {
+        subject
+    } else {
+        format!("{}, {}", subject, period)
+    }
+}
+
+/// One outline entry opening `page_id`, linked to its siblings in `siblings`.
+fn outline_item(title: &str, page_id: ObjectId, parent: ObjectId, siblings: &[ObjectId], index: usize) -> Dictionary {
+    let mut item = dictionary! {
+        "Title" => pdf_text_string(title),
+        "Parent" => parent,
+        "Dest" => vec![page_id.into(), "Fit".into()],
+    };
+    if index > 0 {
+        item.set("Prev", siblings[index - 1]);
+    }
+    if index + 1 < siblings.len() {
+        item.set("Next", siblings[index + 1]);
+    }
+    item
+}
+
+/// Adds `object_id` as `name` to the `category` dictionary (`Font`, `XObject`, ...) of a resources dictionary.
+pub fn add_resource(
+    doc: &mut lopdf::Document,
+    resources_id: ObjectId,
+    category: &[u8],
+    name: &str,
+    object_id: ObjectId,
+) -> Result<(), PromoFinError> {
+    let category_ref = match doc.get_object(resources_id)?.as_dict()?.get(category) {
+        Ok(Object::Reference(id)) => Some(*id),
+        _ => None,
+    };
+    let category_dict = match category_ref {
+        Some(id) => doc.get_object_mut(id)?.as_dict_mut()?,
+        None => {
+            let resources = doc.get_object_mut(resources_id)?.as_dict_mut()?;
+            if !resources.has(category) {
+                resources.set(category, Dictionary::new());
+            }
+            resources.get_mut(category)?.as_dict_mut()?
+        }
+    };
+    category_dict.set(name, object_id);
+    Ok(())
+}
+
 pub fn write_rows_to_pdf_container<W:Write>(
     customer: &String,
-    _: usize,
+    section_index: usize,
     times_qualified: i64,
     data: Vec<Vec<Vec<String>>>,
+    sales_lines: &HashMap<Vec<String>, usize>,
+    layout: &LayoutConfig,
     save_to: &mut W
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), PromoFinError> {
 
-    let dpi = 72.0;
-    let mut pdf_draw = PdfDrawInfo{ pdf: vec![]};
+    let fonts = &layout.fonts;
+    let mut builder = PdfReportBuilder::new(&layout.detail_page, layout)?;
+    let border_color = builder.theme().secondary_color;
 
-    let mut pdf_manager = Manager::new(11.0,8.5, dpi, 0.25, 0.25);
+    let mut promo_for = TextBox::new(format!("Promotion for: {}", customer), FontInfo::new(fonts.small, Font::Helvetica), Some(TextAlignment::LeftBottom),None,None, None);
+    let mut times_qual = TextBox::new(format!("Times Qualified: {}", times_qualified), FontInfo::new(fonts.small, Font::Helvetica), Some(TextAlignment::LeftBottom),None,None, None);
 
-    let mut promo_for = TextBox::new(format!("Promotion for: {}", customer), FontInfo::new(10.0, Font::Helvetica), Some(TextAlignment::LeftBottom),None,None, None);
-    let mut times_qual = TextBox::new(format!("Times Qualified: {}", times_qualified), FontInfo::new(10.0, Font::Helvetica), Some(TextAlignment::LeftBottom),None,None, None);
-    let mut space = TextBox::new("", FontInfo::new(10.0, Font::Helvetica), Some(TextAlignment::LeftBottom),None,None, None);
+    let period = &layout.decorations.promo_period;
+    builder.describe(DocumentInfo {
+        title: format!("{} Promo {} Detail", customer, section_index + 1),
+        subject: with_period(format!("Qualifying purchases for {}, Promo {}", customer, section_index + 1), period),
+        keywords: vec![customer.clone(), format!("Promo {}", section_index + 1), period.clone()],
+    });
 
-    let mut placement_handle = pdf_manager.get_placement_handle(2..50, false);
-    placement_handle.set_pixel_height(0.27 * dpi);
-    let borders: &Option<RefCell<Vec<Border>>> = &Some(RefCell::new(Vec::new()));
-    placement_handle.draw( &mut promo_for, &mut pdf_draw, &borders );
-    placement_handle = pdf_manager.get_placement_handle(2..20 , false);
-    placement_handle.set_pixel_height(0.27 * dpi);
-    placement_handle.draw( &mut times_qual, &mut pdf_draw, &borders );
-    placement_handle = pdf_manager.get_placement_handle(2..20, false );
-    placement_handle.set_pixel_height(0.27 * dpi);
-    placement_handle.draw(  &mut space, &mut pdf_draw, &borders );
+    builder.protect_for(customer)?;
 
+    builder.text_row(2..50, false, 0.27, TextStyle::Small, &mut promo_for);
+    builder.start_customer(customer);
+    builder.start_section(&format!("Promo {}", section_index + 1));
+    builder.text_row(2..20, false, 0.27, TextStyle::Small, &mut times_qual);
+    builder.spacer(2..20, 0.27, TextStyle::Small);
 
-    let col_size: Vec<usize> = vec![10,20,12,4,11,25,9];
+
+    let col_size: Vec<usize> = scale_columns(&DETAIL_COLUMN_WEIGHTS, DETAIL_TABLE_COLUMNS.len());
     let header = vec![
         "Ship Date",
         "Customer Name",
@@ -79,7 +749,15 @@ pub fn write_rows_to_pdf_container<W:Write>(
         let mut row_qty_sum = 0.0;
 
         for row in &data[cur_db_rows_index] {
-            let valid_qty = row[3].parse::<f64>()?;
+            let invalid_cell = |column: &'static str, value: &String| PromoFinError::InvalidCell {
+                customer: customer.clone(),
+                section: section_index,
+                line: sales_lines.get(row).cloned(),
+                column,
+                value: value.clone(),
+            };
+            let valid_qty = row[3].trim().parse::<f64>().map_err(|_| invalid_cell("Qty", &row[3]))?;
+            parse_price(&row[6]).ok_or_else(|| invalid_cell("Sale Price", &row[6]))?;
             row_qty_sum = row_qty_sum + valid_qty;
         }
         if row_qty_sum < 0.0001 {
@@ -98,111 +776,182 @@ pub fn write_rows_to_pdf_container<W:Write>(
             continue;
         }
 
-        let mut placement_handle = pdf_manager.get_placement_handle(2..col_size.clone().into_iter().sum::<usize>() + 2, false );
+        let mut placement_handle = builder.manager.get_placement_handle(DETAIL_TABLE_COLUMNS, false );
 
         let data_column_alignment = vec![TextAlignment::LeftJustifyBottom(0.05),TextAlignment::LeftJustifyBottom(0.05),TextAlignment::LeftJustifyBottom(0.05),TextAlignment::RightJustifyBottom(0.05),TextAlignment::LeftJustifyBottom(0.05),TextAlignment::LeftBottom, TextAlignment::RightJustifyBottom(0.05)];
         let trans_data = data[cur_db_rows_index].clone().into_iter().map(|x| RowData::new(x,RowDataTypes::default())).collect::<Vec<RowData>>();
         let dta = RowData::new(header.clone(),RowDataTypes::default());
         let trans_header = Some(&dta);
-        let mut list_box = ListBox::new(&trans_data, col_size.clone(), trans_header, &mut pdf_manager, FontInfo::new(10.0,Font::Helvetica), FontInfo::new(12.0, Font::Helvetica), ListBoxBorder::All(1.4,1.4), None);
+        let mut list_box = ListBox::new(&trans_data, col_size.clone(), trans_header, &mut builder.manager, FontInfo::new(fonts.small, Font::Helvetica), FontInfo::new(fonts.body, Font::Helvetica), ListBoxBorder::All(1.4,1.4), None);
 
         list_box.set_item_column_alignments(data_column_alignment);
         list_box.set_header_column_alignments(vec![TextAlignment::LeftJustifyCenter(0.05);header.len()]);
-        list_box.set_border_color((0.0,0.0,0.0));
+        list_box.set_border_color(border_color);
         list_box.header_has_border(false);
         let row_data_types = vec![TypeOfItem::String,TypeOfItem::String,TypeOfItem::String,TypeOfItem::Number(2),TypeOfItem::String,TypeOfItem::String,TypeOfItem::Currency(2)];
         list_box.set_row_types(row_data_types);
 
-        placement_handle.draw( &mut list_box, &mut pdf_draw, &borders);
+        builder.use_styles(&[TextStyle::Small, TextStyle::Body]);
+        placement_handle.draw( &mut list_box, &mut builder.pdf_draw, &builder.borders);
 
         total_qty = sec_total_qty[cur_db_rows_index].unwrap();
-        let mut qty_total = TextBox::new(format!("Total Quantity: {}", total_qty), FontInfo::new(14.0, Font::Helvetica), Some(TextAlignment::RightJustifyCenter(0.05)),None,None, None);
+        let mut qty_total = TextBox::new(format!("Total Quantity: {}", total_qty), FontInfo::new(fonts.heading, Font::Helvetica), Some(TextAlignment::RightJustifyCenter(0.05)),None,None, None);
 
-        let qty_column_range_start = col_size[0..3].into_iter().sum::<usize>() + 2;
+        let qty_column_range_start = col_size[0..3].into_iter().sum::<usize>() + DETAIL_TABLE_COLUMNS.start;
 
-        placement_handle = pdf_manager.get_placement_handle(2..qty_column_range_start + col_size[3], false );
-        placement_handle.set_pixel_height(0.27 * dpi);
-        placement_handle.draw( &mut qty_total, &mut pdf_draw, &borders );
+        builder.text_row(DETAIL_TABLE_COLUMNS.start..qty_column_range_start + col_size[3], false, 0.27, TextStyle::Heading, &mut qty_total);
 
         //is not last row.
         if cur_db_rows_index < ends_on_row {
-            let mut space = TextBox::new("", FontInfo::new(10.0, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
-
-            let mut placement_handle = pdf_manager.get_placement_handle(0..50, false);
-            placement_handle.set_pixel_height(0.27 * dpi);
-            placement_handle.draw(&mut space, &mut pdf_draw, &borders);
-
+            builder.spacer(0..50, 0.27, TextStyle::Small);
         }
 
     }
 
-    for group_rec in pdf_manager.get_groups() {
-        for page_index in 0..group_rec.1.len() {
+    builder.draw_groups(0.0, 5.0, |_| (1.0,0.0,0.0));
 
-            let mut pl: PlacementInfo = PlacementInfo::default();
-            pl.page_number = page_index;
-            pl.rec = group_rec.1[page_index].clone();
-            pl.page_size_info = pdf_manager.get_page_info();
+    builder.finish(save_to)
+}
 
-            draw_rectangle(&mut pdf_draw,
-                           &pl,
-                           5.0,
-                           (1.0,0.0,0.0));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A builder with `customers` drawn from the top of a page each, given as their name and
+    /// the page count they take.
+    fn paged_builder(layout: &LayoutConfig, customers: &[(&str, usize)]) -> PdfReportBuilder {
+        let mut builder = PdfReportBuilder::new(&layout.missing_page, layout).unwrap();
+        let mut first_page = true;
+        for (customer, pages) in customers {
+            for page in 0..*pages {
+                let mut txt = TextBox::new(customer.to_string(), FontInfo::new(layout.fonts.body, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
+                builder.text_row(1..99, !first_page, 0.25, TextStyle::Body, &mut txt);
+                first_page = false;
+                if page == 0 {
+                    builder.start_customer(customer);
+                }
+            }
         }
+        builder
     }
 
-    match borders {
-        Some(brd) => {
+    fn shown_text(operations: &[Operation]) -> Vec<String> {
+        operations
+            .iter()
+            .filter(|operation| operation.operator == "Tj")
+            .map(|operation| String::from_utf8_lossy(operation.operands[0].as_str().unwrap()).into_owned())
+            .collect()
+    }
 
-            let hm = brd.to_owned().into_inner();
-            for border in hm.into_iter() {
+    #[test]
+    fn running_text_counts_pages_per_document_and_per_customer() {
+        let mut layout = LayoutConfig::default();
+        layout.decorations.header = Some(RunningText { left: "{customer}".to_owned(), right: "Page {page} of {pages}".to_owned(), ..RunningText::default() });
+        layout.decorations.footer = Some(RunningText { center: "{customer_page}/{customer_pages}".to_owned(), ..RunningText::default() });
+        let builder = paged_builder(&layout, &[("Acme", 2), ("Bolt", 2)]);
+        assert_eq!(builder.manager.get_page_cnt() + 1, 4);
+
+        let pages: Vec<Vec<String>> = (0..4).map(|page| shown_text(&builder.decoration_operations(page, 4))).collect();
+        assert_eq!(pages[0], vec!["Acme", "Page 1 of 4", "1/2"]);
+        assert_eq!(pages[1], vec!["Acme", "Page 2 of 4", "2/2"]);
+        assert_eq!(pages[2], vec!["Bolt", "Page 3 of 4", "1/2"]);
+        assert_eq!(pages[3], vec!["Bolt", "Page 4 of 4", "2/2"]);
+    }
 
-                draw_rectangle(&mut pdf_draw,
-                               &border.rec,
-                               border.pixel_size,
-                               border.color);
-            };
-        },
-        None => {
+    #[test]
+    fn pages_are_undecorated_by_default() {
+        let layout = LayoutConfig::default();
+        let mut builder = paged_builder(&layout, &[("Acme", 1)]);
+        assert!(builder.decoration_operations(0, 1).is_empty());
+
+        // nothing drawn outside the containers, so the extra Helvetica resource is not even added
+        let doc = builder.build_document().unwrap();
+        let content = doc.get_page_content(doc.get_pages()[&1]).unwrap();
+        let operations = Content::decode(&content).unwrap().operations;
+        assert!(operations.iter().all(|operation| operation.operator != "Tf"
+            || operation.operands[0].as_name().unwrap() != HELVETICA_RESOURCE.as_bytes()));
+    }
+
+    #[test]
+    fn scaled_columns_fill_the_span_in_proportion() {
+        assert_eq!(scale_columns(&DETAIL_COLUMN_WEIGHTS, DETAIL_TABLE_COLUMNS.len()), vec![11, 21, 13, 4, 12, 26, 9]);
+        assert_eq!(scale_columns(&[1.0; 5], 49), vec![10, 10, 10, 10, 9]);
+        // every column keeps at least one grid column, even past the span
+        assert_eq!(scale_columns(&[0.01, 0.01, 10.0], 3), vec![1, 1, 1]);
+        assert_eq!(scale_columns(&[1.0; 5], 3), vec![1; 5]);
+    }
 
+    #[test]
+    fn styles_of_one_size_are_drawn_in_their_own_faces() {
+        let mut layout = LayoutConfig::default();
+        layout.fonts.heading = layout.fonts.title;
+        layout.font_faces.heading = Some("tests/fixtures/DejaVuSansMono-Oblique.ttf".to_owned());
+        let mut builder = PdfReportBuilder::new(&layout.missing_page, &layout).unwrap();
+        for style in [TextStyle::Title, TextStyle::Heading].iter() {
+            let mut txt = TextBox::new("Acme".to_owned(), FontInfo::new(layout.fonts.title, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
+            builder.text_row(1..99, false, 0.25, *style, &mut txt);
         }
+        let fonts: Vec<&[u8]> = builder.pdf_draw.pdf[0]
+            .iter()
+            .filter(|operation| operation.operator == "Tf")
+            .map(|operation| operation.operands[0].as_name().unwrap())
+            .collect();
+        assert_eq!(fonts.len(), 2);
+        assert_ne!(fonts[0], b"PFEmb0");
+        assert_eq!(fonts[1], b"PFEmb0");
     }
 
-    let mut doc = lopdf::Document::with_version("1.5");
-    let pages_id = doc.new_object_id();
+    fn referenced<'a>(doc: &'a lopdf::Document, dict: &Dictionary, key: &[u8]) -> &'a Dictionary {
+        let id = dict.get(key).unwrap().as_reference().unwrap();
+        doc.get_object(id).unwrap().as_dict().unwrap()
+    }
 
-    let mut v:Vec<lopdf::Object> = Vec::new();
-    let resources_id = create_font_recource_id(&mut doc);
+    fn title(item: &Dictionary) -> String {
+        String::from_utf8_lossy(item.get(b"Title").unwrap().as_str().unwrap()).into_owned()
+    }
 
-    for page in 0..pdf_manager.get_page_cnt() + 1 {
-        let content = Content {
-            operations: pdf_draw.pdf[page].clone()//draw_data_pdf(0.2, 1.0, &mut columns)
-        };
-        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
-        let page_id = doc.add_object(dictionary! {
-            "Type" => "Page",
-            "Parent" => pages_id,
-            "Contents" => content_id,
-            });
-        v.push( page_id.into() )
-    };
-    let page_count = v.len() as i32;
-    let pages = dictionary! {
-		"Type" => "Pages",
-		"Kids" => v,
-		"Count" => page_count,
-		"Resources" => resources_id,
-		"MediaBox" => vec![0.into(), 0.into(), (pdf_manager.get_page_pixel_dims().0).into(), (pdf_manager.get_page_pixel_dims().1).into()],
-	};
-    doc.objects.insert(pages_id, Object::Dictionary(pages));
-    let catalog_id = doc.add_object(dictionary! {
-		"Type" => "Catalog",
-		"Pages" => pages_id,
-	});
-    doc.trailer.set("Root", catalog_id);
-    doc.compress();
-
-    doc.save_to(save_to)?;
+    fn destination(item: &Dictionary) -> ObjectId {
+        item.get(b"Dest").unwrap().as_array().unwrap()[0].as_reference().unwrap()
+    }
 
-    Ok(())
+    #[test]
+    fn outline_has_a_customer_entry_per_customer_with_its_sections() {
+        let layout = LayoutConfig::default();
+        let mut builder = paged_builder(&layout, &[("Acme", 1)]);
+        builder.start_section("Promo 1");
+        let mut txt = TextBox::new("Promo 2".to_owned(), FontInfo::new(layout.fonts.body, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
+        builder.text_row(1..99, true, 0.25, TextStyle::Body, &mut txt);
+        builder.start_section("Promo 2");
+        let mut txt = TextBox::new("Bolt".to_owned(), FontInfo::new(layout.fonts.body, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
+        builder.text_row(1..99, true, 0.25, TextStyle::Body, &mut txt);
+        builder.start_customer("Bolt");
+        builder.enable_outline();
+
+        let mut pdf = Vec::new();
+        builder.finish(&mut pdf).unwrap();
+        let doc = lopdf::Document::load_mem(&pdf).unwrap();
+        let pages = doc.get_pages();
+        assert_eq!(pages.len(), 3);
+
+        let catalog = referenced(&doc, &doc.trailer, b"Root");
+        assert_eq!(catalog.get(b"PageMode").unwrap().as_name().unwrap(), b"UseOutlines");
+        let outlines = referenced(&doc, catalog, b"Outlines");
+        assert_eq!(outlines.get(b"Count").unwrap().as_i64().unwrap(), 2);
+
+        let acme = referenced(&doc, outlines, b"First");
+        assert_eq!(title(acme), "Acme");
+        assert_eq!(destination(acme), pages[&1]);
+        assert_eq!(acme.get(b"Count").unwrap().as_i64().unwrap(), -2);
+        let promo_1 = referenced(&doc, acme, b"First");
+        assert_eq!((title(promo_1), destination(promo_1)), ("Promo 1".to_owned(), pages[&1]));
+        let promo_2 = referenced(&doc, promo_1, b"Next");
+        assert_eq!((title(promo_2), destination(promo_2)), ("Promo 2".to_owned(), pages[&2]));
+        assert_eq!(acme.get(b"Last").unwrap().as_reference().unwrap(), promo_1.get(b"Next").unwrap().as_reference().unwrap());
+
+        let bolt = referenced(&doc, acme, b"Next");
+        assert_eq!(title(bolt), "Bolt");
+        assert_eq!(destination(bolt), pages[&3]);
+        assert!(bolt.get(b"First").is_err());
+        assert_eq!(outlines.get(b"Last").unwrap().as_reference().unwrap(), acme.get(b"Next").unwrap().as_reference().unwrap());
+    }
 }
@@ -0,0 +1,199 @@
+use lopdf::{dictionary, Dictionary, Object, ObjectId, Stream};
+use serde::{Deserialize, Serialize};
+use crate::error::PromoFinError;
+
+/// PDF/A-2b output for documents kept as qualification evidence.
+///
+/// Needs `layout.font_faces.default` so that every font is embedded, and an ICC profile for
+/// the output intent, usually sRGB. XMP metadata is always written in this mode.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ArchivalOptions {
+    pub enabled: bool,
+    /// ICC profile embedded as the output intent's destination profile.
+    pub icc_profile: Option<String>,
+    /// Registry name of the profile's characterisation.
+    pub output_condition: String,
+}
+
+impl Default for ArchivalOptions {
+    fn default() -> Self {
+        Self { enabled: false, icc_profile: None, output_condition: "sRGB IEC61966-2.1".to_owned() }
+    }
+}
+
+/// PDF version written in archival mode; PDF/A-2 is based on PDF 1.7.
+pub const PDFA_VERSION: &str = "1.7";
+
+/// XMP identification schema marking a document as PDF/A-2b.
+pub const PDFA_XMP_SCHEMA: &str = "<rdf:Description rdf:about=\"\" xmlns:pdfaid=\"http://www.aiim.org/pdfa/ns/id/\">\n\
+<pdfaid:part>2</pdfaid:part>\n<pdfaid:conformance>B</pdfaid:conformance>\n</rdf:Description>\n";
+
+/// Reads the ICC profile named by `options`, checking that archival output can be produced.
+pub fn load_icc_profile(options: &ArchivalOptions) -> Result<Vec<u8>, PromoFinError> {
+    let path = options.icc_profile.as_ref().ok_or_else(|| PromoFinError::Archival {
+        problems: vec!["an ICC profile is required for the output intent".to_owned()],
+    })?;
+    std::fs::read(path).map_err(|e| PromoFinError::Archival {
+        problems: vec![format!("could not read ICC profile '{}': {}", path, e)],
+    })
+}
+
+/// Adds an RGB output intent carrying `icc_profile`, returning the intent to list in the catalog.
+pub fn add_output_intent(doc: &mut lopdf::Document, options: &ArchivalOptions, icc_profile: Vec<u8>) -> ObjectId {
+    let profile_id = doc.add_object(Stream::new(dictionary! { "N" => 3 }, icc_profile));
+    doc.add_object(dictionary! {
+        "Type" => "OutputIntent",
+        "S" => "GTS_PDFA1",
+        "OutputConditionIdentifier" => Object::string_literal(options.output_condition.as_str()),
+        "Info" => Object::string_literal(options.output_condition.as_str()),
+        "DestOutputProfile" => profile_id,
+    })
+}
+
+/// Structural PDF/A-2b checks on a built document, returning a description of each problem.
+///
+/// This is not a full validator; it covers what the reports could get wrong: version, file
+/// identifier, encryption, XMP identification, output intent, font embedding and CMYK images.
+pub fn check_pdfa(doc: &lopdf::Document) -> Vec<String> {
+    let mut problems = Vec::new();
+    if doc.version.as_str() > PDFA_VERSION {
+        problems.push(format!("PDF version {} is newer than {}", doc.version, PDFA_VERSION));
+    }
+    if doc.trailer.get(b"ID").is_err() {
+        problems.push("trailer has no file identifier".to_owned());
+    }
+    if doc.trailer.get(b"Encrypt").is_ok() {
+        problems.push("document is encrypted".to_owned());
+    }
+
+    match doc.catalog() {
+        Ok(catalog) => {
+            let has_identification = catalog
+                .get(b"Metadata")
+                .and_then(Object::as_reference)
+                .and_then(|id| doc.get_object(id))
+                .and_then(Object::as_stream)
+                .map(|stream| String::from_utf8_lossy(&stream.content).contains("<pdfaid:part>2</pdfaid:part>"))
+                .unwrap_or(false);
+            if !has_identification {
+                problems.push("catalog has no XMP metadata identifying PDF/A-2".to_owned());
+            }
+            let has_intent = match catalog.get(b"OutputIntents").and_then(Object::as_array) {
+                Ok(intents) => intents.iter().any(|intent| {
+                    doc.dereference(intent)
+                        .and_then(|(_, intent)| intent.as_dict())
+                        .map(|intent| intent.get(b"DestOutputProfile").is_ok())
+                        .unwrap_or(false)
+                }),
+                Err(_) => false,
+            };
+            if !has_intent {
+                problems.push("catalog has no output intent with a destination profile".to_owned());
+            }
+        }
+        Err(_) => problems.push("trailer has no catalog".to_owned()),
+    }
+
+    for (id, object) in &doc.objects {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &stream.dict,
+            _ => continue,
+        };
+        if let Some(problem) = font_problem(doc, *id, dict).or_else(|| image_problem(*id, dict)) {
+            problems.push(problem);
+        }
+    }
+    problems
+}
+
+fn name_of<'a>(dict: &'a Dictionary, key: &[u8]) -> &'a [u8] {
+    dict.get(key).and_then(Object::as_name).unwrap_or(b"")
+}
+
+fn font_problem(doc: &lopdf::Document, id: ObjectId, dict: &Dictionary) -> Option<String> {
+    if name_of(dict, b"Type") != b"Font" {
+        return None;
+    }
+    // Type0 fonts are checked through their descendant, Type3 glyphs are content streams
+    let subtype = name_of(dict, b"Subtype");
+    if subtype == b"Type0" || subtype == b"Type3" {
+        return None;
+    }
+    let embedded = dict
+        .get(b"FontDescriptor")
+        .and_then(|descriptor| doc.dereference(descriptor))
+        .and_then(|(_, descriptor)| descriptor.as_dict())
+        .map(|descriptor| {
+            descriptor.has(b"FontFile") || descriptor.has(b"FontFile2") || descriptor.has(b"FontFile3")
+        })
+        .unwrap_or(false);
+    if embedded {
+        None
+    } else {
+        Some(format!(
+            "font {} {} is not embedded",
+            String::from_utf8_lossy(name_of(dict, b"BaseFont")),
+            id.0
+        ))
+    }
+}
+
+fn image_problem(id: ObjectId, dict: &Dictionary) -> Option<String> {
+    if name_of(dict, b"Subtype") == b"Image" && name_of(dict, b"ColorSpace") == b"DeviceCMYK" {
+        Some(format!("image {} uses DeviceCMYK with an RGB output intent", id.0))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backfat::container_objects::text_box::{TextBox, TextAlignment};
+    use backfat::font::font_info::FontInfo;
+    use backfat::font::font_sizes::Font;
+    use crate::config::{LayoutConfig, RunningText, TextStyle};
+    use crate::pdf::PdfReportBuilder;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn archival_document_passes_the_structural_check() {
+        let mut layout = LayoutConfig::default();
+        layout.font_faces.default = Some(fixture("DejaVuSansMono-Oblique.ttf"));
+        layout.archival = ArchivalOptions {
+            enabled: true,
+            icc_profile: Some(fixture("test-rgb.icc")),
+            ..ArchivalOptions::default()
+        };
+        layout.decorations.promo_period = "May 1–Jul 31 2020".to_owned();
+        layout.decorations.header = Some(RunningText { left: "{customer}".to_owned(), right: "{period}".to_owned(), ..RunningText::default() });
+        layout.decorations.footer = Some(RunningText { center: "Page {page} of {pages}".to_owned(), ..RunningText::default() });
+
+        let mut builder = PdfReportBuilder::new(&layout.missing_page, &layout).unwrap();
+        let mut txt = TextBox::new("For Customer: Ünïcode & Co".to_owned(), FontInfo::new(layout.fonts.title, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
+        builder.text_row(1..99, false, 0.25, TextStyle::Title, &mut txt);
+        builder.start_customer("Ünïcode & Co");
+
+        let doc = builder.build_document().unwrap();
+        assert_eq!(check_pdfa(&doc), Vec::<String>::new());
+        assert_eq!(doc.version, PDFA_VERSION);
+    }
+
+    #[test]
+    fn plain_document_fails_the_structural_check() {
+        let layout = LayoutConfig::default();
+        let mut builder = PdfReportBuilder::new(&layout.missing_page, &layout).unwrap();
+        let mut txt = TextBox::new("Missing Report".to_owned(), FontInfo::new(layout.fonts.title, Font::Helvetica), Some(TextAlignment::LeftBottom), None, None, None);
+        builder.text_row(1..99, false, 0.25, TextStyle::Title, &mut txt);
+
+        let problems = check_pdfa(&builder.build_document().unwrap());
+        assert!(problems.iter().any(|p| p.contains("XMP")));
+        assert!(problems.iter().any(|p| p.contains("output intent")));
+        assert!(problems.iter().any(|p| p.contains("not embedded")));
+    }
+}
@@ -0,0 +1,292 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use serde::Serialize;
+use promo_input::general::and_or::AndOrType;
+use promo_input::general::promo_json::Promotion;
+use crate::error::PromoFinError;
+use crate::missing_report::{DetailColumns, MissingPartNumber, NeededSections};
+use crate::pdf::parse_price;
+
+/// Unit prices per part number, from past sales and an optional price list.
+///
+/// A price list entry wins over history, and a customer's own purchases win over what
+/// everyone else paid. `Sale Price` cells are read as the price of one unit.
+#[derive(Debug, Clone, Default)]
+pub struct PriceBook {
+    list: HashMap<String, f64>,
+    customer_history: HashMap<String, HashMap<String, f64>>,
+    history: HashMap<String, f64>,
+}
+
+/// Running quantity weighted price sum and quantity of one part number.
+type PriceSum = HashMap<String, (f64, f64)>;
+
+fn add_sale(sums: &mut PriceSum, part_number: &str, price: f64, qty: f64) {
+    let sum = sums.entry(part_number.to_owned()).or_insert((0.0, 0.0));
+    sum.0 = sum.0 + price * qty;
+    sum.1 = sum.1 + qty;
+}
+
+fn averages(sums: PriceSum) -> HashMap<String, f64> {
+    sums.into_iter()
+        .filter(|(_, (_, qty))| *qty > 0.0)
+        .map(|(part_number, (total, qty))| (part_number, total / qty))
+        .collect()
+}
+
+impl PriceBook {
+    /// Quantity weighted average `Sale Price` of every part number in the qualifying rows.
+    ///
+    /// Rows whose quantity or price is not a positive number are skipped.
+    pub fn from_history(data: &HashMap<String, Promotion>, columns: &DetailColumns) -> Self {
+        let mut everyone = PriceSum::new();
+        let mut customer_history = HashMap::new();
+        for (customer, promotion) in data {
+            let mut own = PriceSum::new();
+            for section in &promotion.promo_sections {
+                for part in &section.part {
+                    for type_prod in &part.type_prod {
+                        for row in &type_prod.found_numbers {
+                            let qty = row[columns.qty].value.trim().parse::<f64>().unwrap_or(0.0);
+                            let price = parse_price(&row[columns.sales].value).unwrap_or(0.0);
+                            if qty <= 0.0 || price <= 0.0 {
+                                continue;
+                            }
+                            let part_number = row[columns.part_number].value.trim();
+                            add_sale(&mut own, part_number, price, qty);
+                            add_sale(&mut everyone, part_number, price, qty);
+                        }
+                    }
+                }
+            }
+            customer_history.insert(customer.clone(), averages(own));
+        }
+        Self { list: HashMap::new(), customer_history, history: averages(everyone) }
+    }
+
+    /// Reads a price list csv with `Part Number` and `Price` columns.
+    pub fn load_price_list(&mut self, path: &str) -> Result<(), PromoFinError> {
+        let mut reader = csv::Reader::from_path(path)?;
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| headers.iter().position(|h| h.trim().eq_ignore_ascii_case(name));
+        let (part_column, price_column) = match (column("Part Number"), column("Price")) {
+            (Some(part), Some(price)) => (part, price),
+            _ => {
+                return Err(PromoFinError::Config {
+                    path: path.to_owned(),
+                    message: "price list needs Part Number and Price columns".to_owned(),
+                })
+            }
+        };
+        for record in reader.records() {
+            let record = record?;
+            let part_number = record.get(part_column).unwrap_or("").trim();
+            if let Some(price) = record.get(price_column).and_then(parse_price) {
+                if !part_number.is_empty() {
+                    self.list.insert(part_number.to_owned(), price);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn unit_price(&self, customer: &str, part_number: &str) -> Option<f64> {
+        let part_number = part_number.trim();
+        self.list
+            .get(part_number)
+            .or_else(|| self.customer_history.get(customer).and_then(|own| own.get(part_number)))
+            .or_else(|| self.history.get(part_number))
+            .cloned()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RecommendedPurchase {
+    pub part_number: String,
+    pub quantity: i64,
+    /// `None` when no price is known for the part.
+    pub unit_price: Option<f64>,
+}
+
+/// The cheapest set of purchases that qualifies a section once more.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Recommendation {
+    pub purchases: Vec<RecommendedPurchase>,
+    /// Cost of the priced purchases.
+    pub total_cost: f64,
+    /// False when some purchase has no known price, so `total_cost` is a lower bound.
+    pub complete: bool,
+}
+
+/// Cheapest priced part number of a requirement, or its first part number when none is priced.
+fn cheapest_part(customer: &str, item: &MissingPartNumber, prices: &PriceBook) -> Option<RecommendedPurchase> {
+    let priced = item
+        .missing_part_numbers
+        .iter()
+        .filter_map(|part_number| prices.unit_price(customer, part_number).map(|price| (part_number, price)))
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+    let (part_number, unit_price) = match priced {
+        Some((part_number, price)) => (part_number, Some(price)),
+        None => (item.missing_part_numbers.first()?, None),
+    };
+    Some(RecommendedPurchase { part_number: part_number.clone(), quantity: item.amount_needed, unit_price })
+}
+
+/// Picks the cheapest way to cover every still-needed part of a section.
+///
+/// And groups need all of their requirements, Or groups only the cheapest priced one and
+/// `Any(n)` groups the `n` cheapest. Returns `None` when nothing is needed or no price is known.
+pub fn cheapest_path(customer: &str, needed: &[NeededSections], prices: &PriceBook) -> Option<Recommendation> {
+    let mut purchases = Vec::new();
+    for sec in needed {
+        for (join_type, items) in &sec.missing_part_numbers {
+            let candidates: Vec<RecommendedPurchase> = items
+                .iter()
+                .filter(|item| item.amount_needed > 0)
+                .filter_map(|item| cheapest_part(customer, item, prices))
+                .collect();
+            match join_type {
+                AndOrType::Or => purchases.extend(cheapest_first(candidates).into_iter().take(1)),
+                AndOrType::Any(n) => purchases.extend(cheapest_first(candidates).into_iter().take(*n as usize)),
+                AndOrType::And | AndOrType::None => purchases.extend(candidates),
+            }
+        }
+    }
+    if purchases.iter().all(|p| p.unit_price.is_none()) {
+        return None;
+    }
+    Some(Recommendation {
+        total_cost: purchases.iter().map(cost).sum(),
+        complete: purchases.iter().all(|p| p.unit_price.is_some()),
+        purchases,
+    })
+}
+
+/// `candidates` with the priced ones first, cheapest first, and the unpriced in their own order.
+fn cheapest_first(mut candidates: Vec<RecommendedPurchase>) -> Vec<RecommendedPurchase> {
+    candidates.sort_by(|a, b| match (a.unit_price, b.unit_price) {
+        (Some(_), Some(_)) => cost(a).partial_cmp(&cost(b)).unwrap_or(Ordering::Equal),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    });
+    candidates
+}
+
+fn cost(purchase: &RecommendedPurchase) -> f64 {
+    purchase.unit_price.map_or(0.0, |price| price * purchase.quantity as f64)
+}
+
+/// One line summary such as `Recommended: 4 x AB-100 at $2.50, 2 x CD-7 (no price) - estimated $10.00+`.
+pub fn describe_recommendation(recommendation: &Recommendation) -> String {
+    let purchases: Vec<String> = recommendation
+        .purchases
+        .iter()
+        .map(|p| match p.unit_price {
+            Some(price) => format!("{} x {} at ${:.2}", p.quantity, p.part_number, price),
+            None => format!("{} x {} (no price)", p.quantity, p.part_number),
+        })
+        .collect();
+    format!(
+        "Recommended: {} - estimated ${:.2}{}",
+        purchases.join(", "),
+        recommendation.total_cost,
+        if recommendation.complete { "" } else { "+" }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{columns, promotion, sale, section};
+
+    fn listed(prices: &[(&str, f64)]) -> PriceBook {
+        let list = prices.iter().map(|(part_number, price)| (part_number.to_string(), *price)).collect();
+        PriceBook { list, ..PriceBook::default() }
+    }
+
+    fn item(part_numbers: &[&str], amount_needed: i64) -> MissingPartNumber {
+        MissingPartNumber {
+            missing_part_numbers: part_numbers.iter().map(|p| p.to_string()).collect(),
+            amount_needed,
+            qty_per_tier: amount_needed as f64,
+        }
+    }
+
+    fn needed(join_type: AndOrType, items: Vec<MissingPartNumber>) -> Vec<NeededSections> {
+        vec![NeededSections { missing_part_numbers: vec![(join_type, items)] }]
+    }
+
+    fn bought(recommendation: &Recommendation) -> Vec<(&str, i64)> {
+        recommendation.purchases.iter().map(|p| (p.part_number.as_str(), p.quantity)).collect()
+    }
+
+    #[test]
+    fn price_list_wins_over_customer_then_overall_history() {
+        let data: HashMap<String, Promotion> = vec![
+            ("Acme", promotion(vec![section(1, 1, 1, "AB-100", vec![sale("Acme", "1", "AB-100", "$3.00")])])),
+            ("Bolt", promotion(vec![section(1, 1, 3, "AB-100", vec![sale("Bolt", "3", "AB-100", "2.00")])])),
+        ]
+        .into_iter()
+        .map(|(customer, promotion)| (customer.to_owned(), promotion))
+        .collect();
+        let mut prices = PriceBook::from_history(&data, &columns());
+        assert_eq!(prices.unit_price("Acme", "AB-100"), Some(3.0));
+        assert_eq!(prices.unit_price("Bolt", " AB-100 "), Some(2.0));
+        // everyone's four units together, for a customer who never bought the part
+        assert_eq!(prices.unit_price("Cole", "AB-100"), Some(2.25));
+        assert_eq!(prices.unit_price("Acme", "CD-7"), None);
+
+        let path = std::env::temp_dir().join(format!("promo_fin_price_list_{}.csv", std::process::id()));
+        std::fs::write(&path, "Part Number,Price\nAB-100,\"$1,001.50\"\n").unwrap();
+        prices.load_price_list(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(prices.unit_price("Acme", "AB-100"), Some(1001.5));
+        assert_eq!(prices.unit_price("Cole", "AB-100"), Some(1001.5));
+    }
+
+    #[test]
+    fn and_groups_buy_every_requirement_at_its_cheapest_part() {
+        let prices = listed(&[("A1", 4.0), ("A2", 3.0), ("B", 5.0)]);
+        let recommendation = cheapest_path("Acme", &needed(AndOrType::And, vec![item(&["A1", "A2"], 2), item(&["B"], 1)]), &prices).unwrap();
+        assert_eq!(bought(&recommendation), vec![("A2", 2), ("B", 1)]);
+        assert_eq!(recommendation.total_cost, 11.0);
+        assert!(recommendation.complete);
+    }
+
+    #[test]
+    fn or_groups_buy_the_cheapest_alternative() {
+        // four units of A cost more than one of B, though A is cheaper per unit
+        let prices = listed(&[("A", 1.0), ("B", 3.0)]);
+        let recommendation = cheapest_path("Acme", &needed(AndOrType::Or, vec![item(&["A"], 4), item(&["B"], 1)]), &prices).unwrap();
+        assert_eq!(bought(&recommendation), vec![("B", 1)]);
+        assert_eq!(recommendation.total_cost, 3.0);
+    }
+
+    #[test]
+    fn any_groups_buy_their_count_of_the_cheapest_requirements() {
+        let prices = listed(&[("A", 5.0), ("B", 1.0), ("C", 2.0)]);
+        let items = vec![item(&["A"], 1), item(&["X"], 1), item(&["B"], 1), item(&["C"], 1)];
+        let recommendation = cheapest_path("Acme", &needed(AndOrType::Any(2), items.clone()), &prices).unwrap();
+        assert_eq!(bought(&recommendation), vec![("B", 1), ("C", 1)]);
+        assert!(recommendation.complete);
+
+        // unpriced requirements only fill what the priced ones cannot
+        let recommendation = cheapest_path("Acme", &needed(AndOrType::Any(4), items), &prices).unwrap();
+        assert_eq!(bought(&recommendation), vec![("B", 1), ("C", 1), ("A", 1), ("X", 1)]);
+        assert_eq!(recommendation.total_cost, 8.0);
+        assert!(!recommendation.complete);
+    }
+
+    #[test]
+    fn unpriced_parts_are_bought_but_not_costed() {
+        let prices = listed(&[("A", 2.0)]);
+        let recommendation = cheapest_path("Acme", &needed(AndOrType::And, vec![item(&["A"], 3), item(&["X"], 1)]), &prices).unwrap();
+        assert_eq!(bought(&recommendation), vec![("A", 3), ("X", 1)]);
+        assert_eq!(recommendation.total_cost, 6.0);
+        assert!(!recommendation.complete);
+        assert_eq!(describe_recommendation(&recommendation), "Recommended: 3 x A at $2.00, 1 x X (no price) - estimated $6.00+");
+
+        assert_eq!(cheapest_path("Acme", &needed(AndOrType::And, vec![item(&["X"], 1)]), &prices), None);
+    }
+}
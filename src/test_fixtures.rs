@@ -0,0 +1,59 @@
+//! Small promo runs for the unit tests, built without a sales export or promo file.
+//!
+//! Sales rows use the column order of [`columns`]; promotions are deserialised from the
+//! same json shape `promo_input` keeps them in.
+
+use std::collections::HashMap;
+use serde_json::{json, Value};
+use promo_input::general::promo_json::Promotion;
+use crate::missing_report::{DetailColumns, PromoRun};
+use crate::recommend::PriceBook;
+use crate::tiers::QualificationCaps;
+
+pub fn columns() -> DetailColumns {
+    DetailColumns { ship_date: 0, customer_name: 1, order_number: 2, qty: 3, part_number: 4, part_number_desc: 5, sales: 6 }
+}
+
+/// One qualifying sales row.
+pub fn sale(customer: &str, qty: &str, part_number: &str, price: &str) -> Value {
+    let cells = ["2020-05-01", customer, "1001", qty, part_number, "Test part", price];
+    Value::Array(cells.iter().map(|value| json!({ "value": value })).collect())
+}
+
+/// A promo section with one And requirement of `qty_needed` units of `part_number`.
+pub fn section(times_qualified: i64, qty_needed: i64, total_qty: i64, part_number: &str, rows: Vec<Value>) -> Value {
+    json!({
+        "times_section_qualified": times_qualified,
+        "promo_parts_still_needed": [0],
+        "part": [{
+            "part_type": "And",
+            "type_prods_for_next_promo_needed": [0],
+            "type_prod": [{
+                "total_qty": total_qty,
+                "qty_needed": qty_needed,
+                "part_numbers": [part_number],
+                "found_numbers": rows,
+            }],
+        }],
+    })
+}
+
+pub fn promotion(sections: Vec<Value>) -> Promotion {
+    serde_json::from_value(json!({ "promo_sections": sections })).expect("test promotion")
+}
+
+/// A run over `customers`, with prices taken from their rows and no caps.
+pub fn promo_run(customers: Vec<(&str, Promotion)>) -> PromoRun {
+    let data: HashMap<String, Promotion> =
+        customers.into_iter().map(|(name, promotion)| (name.to_owned(), promotion)).collect();
+    let columns = columns();
+    let prices = PriceBook::from_history(&data, &columns);
+    PromoRun { data, columns, prices, caps: QualificationCaps::default(), uncapped_times: HashMap::new(), sales_lines: HashMap::new() }
+}
+
+/// Applies `caps` to `run` the way loading a capped promo does.
+pub fn capped(mut run: PromoRun, caps: QualificationCaps) -> PromoRun {
+    run.uncapped_times = caps.apply(&mut run.data);
+    run.caps = caps;
+    run
+}
@@ -0,0 +1,94 @@
+use lopdf::content::Operation;
+use lopdf::{Object, StringFormat};
+
+/// Resource name of the WinAnsi encoded Helvetica added for text drawn outside backfat containers.
+pub const HELVETICA_RESOURCE: &str = "PFHelv";
+
+/// Helvetica advance widths for WinAnsi codes 32 to 126, in 1/1000 em.
+const HELVETICA_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278,
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556,
+    1015, 667, 667, 722, 722, 667, 611, 778, 722, 278, 500, 667, 556, 833, 722, 778,
+    667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 278, 278, 278, 469, 556,
+    333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500, 222, 833, 556, 556,
+    556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+
+/// Encodes text for a WinAnsi font, replacing characters it cannot show with `?`.
+pub fn encode_win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c {
+            '\u{20}'..='\u{7e}' | '\u{a0}'..='\u{ff}' => c as u8,
+            '\u{20ac}' => 0x80,
+            '\u{2026}' => 0x85,
+            '\u{2018}' => 0x91,
+            '\u{2019}' => 0x92,
+            '\u{201c}' => 0x93,
+            '\u{201d}' => 0x94,
+            '\u{2022}' => 0x95,
+            '\u{2013}' => 0x96,
+            '\u{2014}' => 0x97,
+            _ => b'?',
+        })
+        .collect()
+}
+
+/// Decodes WinAnsi bytes, the inverse of [`encode_win_ansi`].
+pub fn decode_win_ansi(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| match b {
+            0x80 => '\u{20ac}',
+            0x85 => '\u{2026}',
+            0x91 => '\u{2018}',
+            0x92 => '\u{2019}',
+            0x93 => '\u{201c}',
+            0x94 => '\u{201d}',
+            0x95 => '\u{2022}',
+            0x96 => '\u{2013}',
+            0x97 => '\u{2014}',
+            b => *b as char,
+        })
+        .collect()
+}
+
+/// Width in points of WinAnsi encoded text set in Helvetica at `font_size`.
+pub fn helvetica_width(encoded: &[u8], font_size: f64) -> f64 {
+    let units: u32 = encoded.iter().map(|b| helvetica_advance(*b) as u32).sum();
+    units as f64 * font_size / 1000.0
+}
+
+/// Helvetica advance width of one WinAnsi code, in 1/1000 em.
+pub fn helvetica_advance(code: u8) -> u16 {
+    match code {
+        32..=126 => HELVETICA_WIDTHS[(code - 32) as usize],
+        0x96 => 556,
+        0x97 => 1000,
+        0x85 => 1000,
+        _ => 556,
+    }
+}
+
+/// Operations that draw one line of text with its baseline starting at `x`, `y`.
+pub fn text_operations(font: &str, font_size: f64, x: f64, y: f64, encoded: Vec<u8>) -> Vec<Operation> {
+    vec![
+        Operation::new("BT", vec![]),
+        Operation::new("Tf", vec![font.into(), font_size.into()]),
+        Operation::new("Td", vec![x.into(), y.into()]),
+        Operation::new("Tj", vec![Object::String(encoded, StringFormat::Literal)]),
+        Operation::new("ET", vec![]),
+    ]
+}
+
+/// A PDF text string for outline titles and document info: literal when plain ASCII,
+/// otherwise UTF-16BE with a byte order mark.
+pub fn pdf_text_string(text: &str) -> Object {
+    if text.is_ascii() {
+        return Object::string_literal(text);
+    }
+    let mut bytes = vec![0xfe, 0xff];
+    for unit in text.encode_utf16() {
+        bytes.extend_from_slice(&unit.to_be_bytes());
+    }
+    Object::String(bytes, StringFormat::Hexadecimal)
+}
@@ -0,0 +1,171 @@
+use std::path::Path;
+use lopdf::{dictionary, Object, Stream};
+use serde::{Deserialize, Serialize};
+use crate::error::PromoFinError;
+
+/// Colour as red, green and blue components from 0 to 1, as backfat takes them.
+pub type Rgb = (f64, f64, f64);
+
+/// Company branding applied to the missing and detail PDFs.
+///
+/// When a logo or title is set, a brand bar is reserved at the top of every page above the
+/// running header.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Theme {
+    /// PNG or JPEG drawn at the left of the brand bar.
+    pub logo: Option<String>,
+    /// Text beside the logo, usually the company name.
+    pub title: String,
+    pub title_font_size: f64,
+    /// Height of the brand bar; the logo is scaled to fit it.
+    pub bar_height_inch: f64,
+    /// Title text and the "Purchase N more" rows.
+    pub primary_color: Rgb,
+    /// Table borders, the rule under the brand bar and card borders.
+    pub secondary_color: Rgb,
+    /// Background of grouped sections drawn in the card style.
+    pub card_color: Rgb,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            logo: None,
+            title: String::new(),
+            title_font_size: 14.0,
+            bar_height_inch: 0.5,
+            primary_color: (0.3, 0.3, 0.9),
+            secondary_color: (0.0, 0.0, 0.0),
+            card_color: (0.95, 0.95, 0.98),
+        }
+    }
+}
+
+impl Theme {
+    pub fn has_brand_bar(&self) -> bool {
+        self.logo.is_some() || !self.title.is_empty()
+    }
+}
+
+/// A decoded logo ready to be added to a document as an image XObject.
+pub struct LogoImage {
+    pub width: u32,
+    pub height: u32,
+    pub image: Stream,
+    /// Soft mask holding the alpha channel of a transparent PNG.
+    pub soft_mask: Option<Stream>,
+}
+
+/// Reads a PNG or JPEG logo, telling the formats apart by their signature.
+///
+/// JPEG data is embedded unchanged with `DCTDecode`; PNG is decoded to 8 bit gray or RGB
+/// samples and left for `Document::compress` to deflate.
+pub fn load_logo<P: AsRef<Path>>(path: P) -> Result<LogoImage, PromoFinError> {
+    let path = path.as_ref();
+    let image_err = |message: String| PromoFinError::Image { path: path.display().to_string(), message };
+    let bytes = std::fs::read(path).map_err(|e| image_err(e.to_string()))?;
+    if bytes.starts_with(&[0xff, 0xd8]) {
+        jpeg_logo(bytes).map_err(image_err)
+    } else if bytes.starts_with(b"\x89PNG") {
+        png_logo(&bytes).map_err(image_err)
+    } else {
+        Err(image_err("not a PNG or JPEG file".to_owned()))
+    }
+}
+
+fn jpeg_logo(bytes: Vec<u8>) -> Result<LogoImage, String> {
+    let (width, height, components) = jpeg_dimensions(&bytes).ok_or("no JPEG frame header found")?;
+    let color_space = match components {
+        1 => "DeviceGray",
+        3 => "DeviceRGB",
+        4 => "DeviceCMYK",
+        n => return Err(format!("unsupported JPEG with {} components", n)),
+    };
+    let mut image = Stream::new(
+        dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Image",
+            "Width" => width as i64,
+            "Height" => height as i64,
+            "ColorSpace" => color_space,
+            "BitsPerComponent" => 8,
+            "Filter" => "DCTDecode",
+        },
+        bytes,
+    );
+    image.allows_compression = false;
+    Ok(LogoImage { width, height, image, soft_mask: None })
+}
+
+/// Width, height and component count from the first start-of-frame marker.
+fn jpeg_dimensions(bytes: &[u8]) -> Option<(u32, u32, u8)> {
+    let mut pos = 2;
+    while pos + 4 <= bytes.len() {
+        if bytes[pos] != 0xff {
+            return None;
+        }
+        let marker = bytes[pos + 1];
+        if marker == 0xff {
+            pos += 1;
+            continue;
+        }
+        let length = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+        // SOF0 to SOF15, except DHT (c4), JPG (c8) and DAC (cc)
+        if (0xc0..=0xcf).contains(&marker) && marker != 0xc4 && marker != 0xc8 && marker != 0xcc {
+            let frame = bytes.get(pos + 4..pos + 10)?;
+            let height = u16::from_be_bytes([frame[1], frame[2]]) as u32;
+            let width = u16::from_be_bytes([frame[3], frame[4]]) as u32;
+            return Some((width, height, frame[5]));
+        }
+        pos += 2 + length;
+    }
+    None
+}
+
+fn png_logo(bytes: &[u8]) -> Result<LogoImage, String> {
+    let mut decoder = png::Decoder::new(bytes);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info().map_err(|e| e.to_string())?;
+    let mut buffer = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+
+    let (color_space, channels, has_alpha) = match info.color_type {
+        png::ColorType::Grayscale => ("DeviceGray", 1, false),
+        png::ColorType::GrayscaleAlpha => ("DeviceGray", 2, true),
+        png::ColorType::RGB => ("DeviceRGB", 3, false),
+        png::ColorType::RGBA => ("DeviceRGB", 4, true),
+        png::ColorType::Indexed => return Err("palette was not expanded".to_owned()),
+    };
+    let pixel_count = (info.width * info.height) as usize;
+    let color_channels = if has_alpha { channels - 1 } else { channels };
+    let mut samples = Vec::with_capacity(pixel_count * color_channels);
+    let mut alpha = Vec::with_capacity(if has_alpha { pixel_count } else { 0 });
+    // rows are padded to line_size, so walk them rather than the flat buffer
+    for row in buffer.chunks(info.line_size).take(info.height as usize) {
+        for pixel in row[..info.width as usize * channels].chunks(channels) {
+            samples.extend_from_slice(&pixel[..color_channels]);
+            if has_alpha {
+                alpha.push(pixel[color_channels]);
+            }
+        }
+    }
+
+    let image_dict = |color_space: &str| {
+        dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Image",
+            "Width" => info.width as i64,
+            "Height" => info.height as i64,
+            "ColorSpace" => Object::Name(color_space.as_bytes().to_vec()),
+            "BitsPerComponent" => 8,
+        }
+    };
+    let soft_mask = if has_alpha { Some(Stream::new(image_dict("DeviceGray"), alpha)) } else { None };
+    Ok(LogoImage {
+        width: info.width,
+        height: info.height,
+        image: Stream::new(image_dict(color_space), samples),
+        soft_mask,
+    })
+}
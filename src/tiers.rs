@@ -0,0 +1,258 @@
+//! Tier arithmetic for a single promo requirement.
+//!
+//! A requirement asks for `qty_needed` units of a type_prod. Every full multiple of
+//! `qty_needed` claimed earns one tier, so a customer who claimed `c` units has earned
+//! `floor(c / qty_needed)` tiers and needs `(tiers + 1) * qty_needed - c` more for the next.
+//!
+//! Edge cases:
+//! - `qty_needed` of zero or less asks for nothing, so there is no next tier to buy towards.
+//! - A negative claim (returns outweighing purchases) earns nothing and has to be made up:
+//!   claimed -2 against 5 needs 7.
+//! - Quantities may be fractional; tiers still count whole multiples, and a claim within
+//!   [`TOLERANCE`] of a multiple counts as reaching it so float noise cannot lose a tier.
+//!
+//! Caps on how often a customer can qualify apply to whole promo sections, not to single
+//! requirements, so they live in [`QualificationCaps`].
+
+use std::collections::HashMap;
+use promo_input::general::promo_json::Promotion;
+use crate::error::PromoFinError;
+
+/// How close to a multiple of `qty_needed` a claim must be to count as reaching it.
+pub const TOLERANCE: f64 = 1e-9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TierRule {
+    pub qty_needed: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NextTier {
+    /// Units still to claim for the next tier; always more than zero.
+    Needs(f64),
+    /// `qty_needed` is not positive, so the requirement asks for nothing.
+    NotRequired,
+}
+
+impl TierRule {
+    pub fn new(qty_needed: f64) -> Self {
+        Self { qty_needed }
+    }
+
+    /// Tiers earned by `claimed` units.
+    pub fn tiers_earned(&self, claimed: f64) -> u32 {
+        if self.qty_needed <= 0.0 || claimed <= 0.0 {
+            return 0;
+        }
+        let earned = (claimed / self.qty_needed + TOLERANCE).floor();
+        if earned >= u32::MAX as f64 {
+            u32::MAX
+        } else {
+            earned as u32
+        }
+    }
+
+    /// What `claimed` units still lack for the next tier.
+    pub fn next_tier(&self, claimed: f64) -> NextTier {
+        if self.qty_needed <= 0.0 {
+            return NextTier::NotRequired;
+        }
+        let earned = self.tiers_earned(claimed);
+        let remaining = (earned as f64 + 1.0) * self.qty_needed - claimed;
+        // a claim just short of a multiple within tolerance already earned that tier above,
+        // so what is left is the whole next step
+        NextTier::Needs(if remaining <= TOLERANCE { self.qty_needed } else { remaining })
+    }
+}
+
+/// Whole units to buy for `next`, rounding fractional remainders up.
+pub fn units_to_buy(next: NextTier) -> i64 {
+    match next {
+        NextTier::Needs(units) => (units - TOLERANCE).ceil() as i64,
+        NextTier::NotRequired => 0,
+    }
+}
+
+/// Key of a promo section in the promo json holding the most times a customer can qualify for it.
+const CAP_KEY: &str = "max_qualifications";
+
+/// Per-section qualification caps read from the promo json.
+///
+/// `promo_input` does not keep [`CAP_KEY`], so it is read from the sections in
+/// `promo_sections` of the json; sections without it are unlimited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QualificationCaps {
+    sections: Vec<Option<u32>>,
+}
+
+impl QualificationCaps {
+    pub fn load(json_promo_file: &str) -> Result<Self, PromoFinError> {
+        let config_err = |message: String| PromoFinError::Config { path: json_promo_file.to_owned(), message };
+        let text = std::fs::read_to_string(json_promo_file).map_err(|e| config_err(e.to_string()))?;
+        let promo = serde_json::from_str(&text).map_err(|e| config_err(e.to_string()))?;
+        Ok(Self::from_json(&promo))
+    }
+
+    pub fn from_json(promo: &serde_json::Value) -> Self {
+        let sections = promo
+            .get("promo_sections")
+            .and_then(|sections| sections.as_array())
+            .map(|sections| {
+                sections
+                    .iter()
+                    .map(|section| section.get(CAP_KEY).and_then(|cap| cap.as_u64()))
+                    .map(|cap| cap.map(|cap| cap.min(u32::MAX as u64) as u32))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self { sections }
+    }
+
+    /// Most qualifications of section `section_index`, `None` for no limit.
+    pub fn cap(&self, section_index: usize) -> Option<u32> {
+        self.sections.get(section_index).cloned().flatten()
+    }
+
+    /// Whether `times_qualified` is as many as section `section_index` pays out.
+    pub fn reached(&self, section_index: usize, times_qualified: i64) -> bool {
+        self.cap(section_index).map_or(false, |cap| times_qualified >= cap as i64)
+    }
+
+    /// Lowers every section's qualification count to its cap, returning each customer's
+    /// counts from before.
+    pub fn apply(&self, data: &mut HashMap<String, Promotion>) -> HashMap<String, Vec<i64>> {
+        let mut uncapped = HashMap::new();
+        for (customer, promotion) in data.iter_mut() {
+            let before = promotion.promo_sections.iter().map(|s| s.times_section_qualified).collect();
+            for (section_index, section) in promotion.promo_sections.iter_mut().enumerate() {
+                if let Some(cap) = self.cap(section_index) {
+                    section.times_section_qualified = section.times_section_qualified.min(cap as i64);
+                }
+            }
+            uncapped.insert(customer.clone(), before);
+        }
+        uncapped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn needs(qty_needed: f64, claimed: f64) -> f64 {
+        match TierRule::new(qty_needed).next_tier(claimed) {
+            NextTier::Needs(units) => units,
+            NextTier::NotRequired => panic!("{} against {} asks for nothing", claimed, qty_needed),
+        }
+    }
+
+    #[test]
+    fn claiming_exactly_one_tier_needs_a_full_tier_more() {
+        assert_eq!(TierRule::new(5.0).tiers_earned(5.0), 1);
+        assert_eq!(needs(5.0, 5.0), 5.0);
+    }
+
+    #[test]
+    fn exact_multiples_need_a_full_tier_more() {
+        for tiers in 1..=20 {
+            let claimed = 5.0 * tiers as f64;
+            assert_eq!(TierRule::new(5.0).tiers_earned(claimed), tiers);
+            assert_eq!(needs(5.0, claimed), 5.0, "claimed {}", claimed);
+        }
+    }
+
+    #[test]
+    fn zero_needed_asks_for_nothing() {
+        for claimed in &[0.0, 3.0, -2.0] {
+            for qty_needed in &[0.0, -1.0] {
+                let rule = TierRule::new(*qty_needed);
+                assert_eq!(rule.next_tier(*claimed), NextTier::NotRequired);
+                assert_eq!(rule.tiers_earned(*claimed), 0);
+                assert_eq!(units_to_buy(rule.next_tier(*claimed)), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn nothing_claimed_needs_one_tier() {
+        assert_eq!(TierRule::new(5.0).tiers_earned(0.0), 0);
+        assert_eq!(needs(5.0, 0.0), 5.0);
+    }
+
+    #[test]
+    fn negative_claims_have_to_be_made_up() {
+        assert_eq!(TierRule::new(5.0).tiers_earned(-2.0), 0);
+        assert_eq!(needs(5.0, -2.0), 7.0);
+        assert_eq!(units_to_buy(TierRule::new(5.0).next_tier(-2.0)), 7);
+    }
+
+    #[test]
+    fn fractional_quantities_round_up_to_whole_units() {
+        assert_eq!(needs(5.0, 7.5), 2.5);
+        assert_eq!(units_to_buy(TierRule::new(5.0).next_tier(7.5)), 3);
+        assert_eq!(TierRule::new(2.5).tiers_earned(5.0), 2);
+        assert_eq!(needs(2.5, 5.0), 2.5);
+        assert_eq!(units_to_buy(TierRule::new(2.5).next_tier(6.0)), 2);
+    }
+
+    #[test]
+    fn float_noise_does_not_lose_a_tier() {
+        let claimed: f64 = (0..10).map(|_| 0.1).sum();
+        assert!(claimed < 1.0);
+        assert_eq!(TierRule::new(1.0).tiers_earned(claimed), 1);
+        assert!((needs(1.0, claimed) - 1.0).abs() < 1e-6);
+        assert_eq!(units_to_buy(TierRule::new(1.0).next_tier(claimed)), 1);
+
+        assert_eq!(TierRule::new(0.3).tiers_earned(0.1 + 0.2), 1);
+        // just short of the tolerance is still short
+        assert_eq!(TierRule::new(5.0).tiers_earned(5.0 - 1e-6), 0);
+    }
+
+    #[test]
+    fn whole_quantities_always_land_on_the_next_multiple() {
+        for qty_needed in 1..=12i64 {
+            let rule = TierRule::new(qty_needed as f64);
+            for claimed in -12..=120i64 {
+                let units = units_to_buy(rule.next_tier(claimed as f64));
+                assert!(units > 0, "{} against {}", claimed, qty_needed);
+                let reached = claimed + units;
+                assert_eq!(reached % qty_needed, 0, "{} against {}", claimed, qty_needed);
+                assert_eq!(reached / qty_needed, rule.tiers_earned(claimed as f64) as i64 + 1);
+                if claimed >= 0 {
+                    assert!(units <= qty_needed, "{} against {}", claimed, qty_needed);
+                } else {
+                    assert_eq!(units, qty_needed - claimed);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fractional_quantities_land_on_the_next_multiple() {
+        for needed_step in 1..=20 {
+            let qty_needed = needed_step as f64 * 0.25;
+            let rule = TierRule::new(qty_needed);
+            for claimed_step in 0..=200 {
+                let claimed = claimed_step as f64 * 0.1;
+                let remaining = needs(qty_needed, claimed);
+                assert!(remaining > 0.0 && remaining <= qty_needed + 1e-6, "{} against {}", claimed, qty_needed);
+                let tiers = (claimed + remaining) / qty_needed;
+                assert!((tiers - tiers.round()).abs() < 1e-6, "{} against {}", claimed, qty_needed);
+                assert_eq!(tiers.round() as u32, rule.tiers_earned(claimed) + 1);
+                assert!(units_to_buy(NextTier::Needs(remaining)) as f64 >= remaining - 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn caps_are_read_from_each_sections_own_key_only() {
+        let caps = QualificationCaps::from_json(&serde_json::json!({
+            "max_qualifications": 9,
+            "promo_sections": [{ "max_qualifications": 3 }, { "max_tiers": 2 }, {}],
+        }));
+        assert_eq!(caps.cap(0), Some(3));
+        assert_eq!(caps.cap(1), None);
+        assert_eq!(caps.cap(2), None);
+        assert!(caps.reached(0, 3) && !caps.reached(0, 2) && !caps.reached(1, 100));
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::HashSet;
+use std::io::{Seek, Write};
+use rust_xlsxwriter::{ExcelDateTime, Format, Workbook, Worksheet};
+use serde::{Deserialize, Serialize};
+use crate::error::PromoFinError;
+use crate::missing_report::PromoRun;
+use crate::pdf::parse_price;
+use promo_input::general::promo_json::PromoSection;
+
+/// How qualifying detail is split across worksheets.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SheetPer {
+    Customer,
+    Section,
+}
+
+impl Default for SheetPer {
+    fn default() -> Self {
+        SheetPer::Customer
+    }
+}
+
+const HEADER: [&str; 7] = [
+    "Ship Date",
+    "Customer Name",
+    "Order Number",
+    "Qty",
+    "Part Number",
+    "Part Number Description",
+    "Sale Price",
+];
+const QTY_COLUMN: u16 = 3;
+const PRICE_COLUMN: u16 = 6;
+const COLUMN_WIDTHS: [f64; 7] = [12.0, 30.0, 14.0, 8.0, 16.0, 40.0, 12.0];
+
+struct Formats {
+    bold: Format,
+    date: Format,
+    /// Whole quantities, without a decimal point.
+    qty: Format,
+    /// Fractional quantities, to three places.
+    fractional_qty: Format,
+    currency: Format,
+}
+
+/// Writes the qualifying purchase rows of every qualified section to an xlsx workbook.
+///
+/// Each type_prod group is followed by a `Total Quantity` row whose value is a `SUM` formula
+/// over the group's quantity cells, so edits in the workbook keep the totals correct.
+pub fn write_detail_workbook<W: Write + Seek + Send>(
+    promo_run: &PromoRun,
+    sheet_per: SheetPer,
+    write_to: W,
+) -> Result<(), PromoFinError> {
+    let formats = Formats {
+        bold: Format::new().set_bold(),
+        date: Format::new().set_num_format("yyyy-mm-dd"),
+        qty: Format::new().set_num_format("#,##0"),
+        fractional_qty: Format::new().set_num_format("#,##0.###"),
+        currency: Format::new().set_num_format("$#,##0.00"),
+    };
+    let mut workbook = Workbook::new();
+    let mut sheet_names = HashSet::new();
+
+    let mut customers: Vec<&String> = promo_run.data.keys().collect();
+    customers.sort();
+    for customer in customers {
+        let qualified: Vec<(usize, &PromoSection)> = promo_run.data[customer]
+            .promo_sections
+            .iter()
+            .enumerate()
+            .filter(|(_, section)| section.times_section_qualified > 0)
+            .collect();
+        if qualified.is_empty() {
+            continue;
+        }
+        match sheet_per {
+            SheetPer::Customer => {
+                let worksheet = new_sheet(&mut workbook, &mut sheet_names, customer)?;
+                let mut row = 0;
+                for (section_index, section) in qualified {
+                    row += write_section(worksheet, row, customer, section_index, section, promo_run, &formats)?;
+                }
+            }
+            SheetPer::Section => {
+                for (section_index, section) in qualified {
+                    let name = format!("{} P{}", customer, section_index + 1);
+                    let worksheet = new_sheet(&mut workbook, &mut sheet_names, &name)?;
+                    write_section(worksheet, 0, customer, section_index, section, promo_run, &formats)?;
+                }
+            }
+        }
+    }
+
+    workbook.save_to_writer(write_to)?;
+    Ok(())
+}
+
+fn new_sheet<'a>(
+    workbook: &'a mut Workbook,
+    taken: &mut HashSet<String>,
+    name: &str,
+) -> Result<&'a mut Worksheet, PromoFinError> {
+    let name = unique_sheet_name(name, taken);
+    let worksheet = workbook.add_worksheet();
+    worksheet.set_name(name)?;
+    for (col, width) in COLUMN_WIDTHS.iter().enumerate() {
+        worksheet.set_column_width(col as u16, *width)?;
+    }
+    Ok(worksheet)
+}
+
+/// Writes the heading and groups of one section starting at `first_row`, returning the rows used.
+fn write_section(
+    worksheet: &mut Worksheet,
+    first_row: u32,
+    customer: &str,
+    section_index: usize,
+    section: &PromoSection,
+    promo_run: &PromoRun,
+    formats: &Formats,
+) -> Result<u32, PromoFinError> {
+    worksheet.write_string_with_format(
+        first_row,
+        0,
+        format!(
+            "{} - Promo {} - Times Qualified: {}",
+            customer,
+            section_index + 1,
+            section.times_section_qualified
+        ),
+        &formats.bold,
+    )?;
+    let mut row = first_row + 2;
+    for group in promo_run.detail_rows(section).iter().filter(|g| !g.is_empty()) {
+        for (col, title) in HEADER.iter().enumerate() {
+            worksheet.write_string_with_format(row, col as u16, *title, &formats.bold)?;
+        }
+        row += 1;
+        let group_start = row;
+        for cells in group {
+            let invalid_cell = |column: &'static str, value: &String| PromoFinError::InvalidCell {
+                customer: customer.to_owned(),
+                section: section_index,
+                line: promo_run.sales_lines.get(cells).cloned(),
+                column,
+                value: value.clone(),
+            };
+            match parse_date(&cells[0]) {
+                Some(date) => worksheet.write_datetime_with_format(row, 0, &date, &formats.date)?,
+                None => worksheet.write_string(row, 0, cells[0].as_str())?,
+            };
+            worksheet.write_string(row, 1, cells[1].as_str())?;
+            worksheet.write_string(row, 2, cells[2].as_str())?;
+            let qty = cells[3].trim().parse::<f64>().map_err(|_| invalid_cell("Qty", &cells[3]))?;
+            let qty_format = if qty.fract() == 0.0 { &formats.qty } else { &formats.fractional_qty };
+            worksheet.write_number_with_format(row, QTY_COLUMN, qty, qty_format)?;
+            worksheet.write_string(row, 4, cells[4].as_str())?;
+            worksheet.write_string(row, 5, cells[5].as_str())?;
+            let price = parse_price(&cells[6]).ok_or_else(|| invalid_cell("Sale Price", &cells[6]))?;
+            worksheet.write_number_with_format(row, PRICE_COLUMN, price, &formats.currency)?;
+            row += 1;
+        }
+        worksheet.write_string_with_format(row, QTY_COLUMN - 1, "Total Quantity", &formats.bold)?;
+        let qty_column = column_letter(QTY_COLUMN);
+        let formula = format!("=SUM({0}{1}:{0}{2})", qty_column, group_start + 1, row);
+        worksheet.write_formula_with_format(row, QTY_COLUMN, formula.as_str(), &formats.bold)?;
+        row += 2;
+    }
+    Ok(row - first_row)
+}
+
+fn column_letter(col: u16) -> char {
+    (b'A' + col as u8) as char
+}
+
+/// Parses `yyyy-mm-dd` and `m/d/yyyy` ship dates, ignoring any time of day.
+fn parse_date(value: &str) -> Option<ExcelDateTime> {
+    let date = value.trim().split(|c| c == ' ' || c == 'T').next()?;
+    let (year, month, day) = if date.contains('-') {
+        let mut parts = date.split('-');
+        (parts.next()?, parts.next()?, parts.next()?)
+    } else if date.contains('/') {
+        let mut parts = date.split('/');
+        let month = parts.next()?;
+        let day = parts.next()?;
+        (parts.next()?, month, day)
+    } else {
+        return None;
+    };
+    let mut year = year.parse::<u16>().ok()?;
+    if year < 100 {
+        year += 2000;
+    }
+    ExcelDateTime::from_ymd(year, month.parse().ok()?, day.parse().ok()?).ok()
+}
+
+/// Sheet names are limited to 31 characters, may not contain `[]:*?/\` and must be unique
+/// ignoring case.
+fn unique_sheet_name(name: &str, taken: &mut HashSet<String>) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| match c {
+            '[' | ']' | ':' | '*' | '?' | '/' | '\\' => '_',
+            c => c,
+        })
+        .collect();
+    let cleaned = cleaned.trim_matches('\'').trim();
+    let base = if cleaned.is_empty() { "Sheet" } else { cleaned };
+    let mut suffix = 1;
+    loop {
+        let tail = if suffix == 1 { String::new() } else { format!(" ({})", suffix) };
+        let keep = 31 - tail.chars().count();
+        let candidate: String = base.chars().take(keep).collect::<String>() + &tail;
+        if taken.insert(candidate.to_lowercase()) {
+            return candidate;
+        }
+        suffix += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+    use serde_json::json;
+    use crate::test_fixtures::{promo_run, promotion, sale, section};
+
+    fn read_part(xlsx: &[u8], name: &str) -> String {
+        let mut archive = zip::ZipArchive::new(Cursor::new(xlsx)).unwrap();
+        let mut part = String::new();
+        archive.by_name(name).unwrap().read_to_string(&mut part).unwrap();
+        part
+    }
+
+    fn cell<'a>(sheet: &'a str, reference: &str) -> &'a str {
+        let start = sheet.find(&format!("<c r=\"{}\"", reference)).unwrap_or_else(|| panic!("no cell {}", reference));
+        let end = start + sheet[start..].find("</c>").unwrap();
+        &sheet[start..end]
+    }
+
+    fn attribute<'a>(element: &'a str, name: &str) -> &'a str {
+        let key = format!(" {}=\"", name);
+        let start = element.find(&key).unwrap_or_else(|| panic!("no {} in {}", name, element)) + key.len();
+        &element[start..start + element[start..].find('"').unwrap()]
+    }
+
+    /// Number format code of the style `cell` uses, with Excel's built in `#,##0` resolved.
+    fn number_format(styles: &str, cell: &str) -> String {
+        let style: usize = attribute(cell, "s").parse().unwrap();
+        let cell_xfs = &styles[styles.find("<cellXfs").unwrap()..];
+        let xf = cell_xfs.split("<xf ").nth(style + 1).unwrap();
+        let id = attribute(&format!(" {}", xf), "numFmtId").to_owned();
+        if id == "3" {
+            return "#,##0".to_owned();
+        }
+        let custom = styles
+            .split("<numFmt ")
+            .skip(1)
+            .find(|num_fmt| attribute(&format!(" {}", num_fmt), "numFmtId") == id)
+            .unwrap_or_else(|| panic!("no number format {}", id));
+        attribute(&format!(" {}", custom), "formatCode").to_owned()
+    }
+
+    #[test]
+    fn detail_sheet_has_sum_totals_and_quantity_formats() {
+        let mut short_date = sale("Acme", "1.5", "AB-100", "2.50");
+        short_date[0]["value"] = json!("5/2/20");
+        let run = promo_run(vec![(
+            "Acme",
+            promotion(vec![section(1, 5, 7, "AB-100", vec![sale("Acme", "6", "AB-100", "2.50"), short_date])]),
+        )]);
+        let mut xlsx = Cursor::new(Vec::new());
+        write_detail_workbook(&run, SheetPer::Customer, &mut xlsx).unwrap();
+        let xlsx = xlsx.into_inner();
+        let sheet = read_part(&xlsx, "xl/worksheets/sheet1.xml");
+        let styles = read_part(&xlsx, "xl/styles.xml");
+
+        // heading, blank, header, then the rows sorted by ship date
+        assert!(cell(&sheet, "D4").contains("<v>6</v>"));
+        assert_eq!(number_format(&styles, cell(&sheet, "D4")), "#,##0");
+        assert!(cell(&sheet, "D5").contains("<v>1.5</v>"));
+        assert_eq!(number_format(&styles, cell(&sheet, "D5")), "#,##0.###");
+        assert!(cell(&sheet, "D6").contains("<f>SUM(D4:D5)</f>"));
+        assert_eq!(number_format(&styles, cell(&sheet, "G4")), "$#,##0.00");
+
+        // 2020-05-01 and 5/2/20 are Excel days 43952 and 43953
+        assert!(cell(&sheet, "A4").contains("<v>43952</v>"));
+        assert!(cell(&sheet, "A5").contains("<v>43953</v>"));
+        assert_eq!(number_format(&styles, cell(&sheet, "A5")), "yyyy-mm-dd");
+    }
+
+    #[test]
+    fn ship_dates_in_either_order_parse() {
+        let day = |value: &str| parse_date(value).map(|date| date.to_excel());
+        assert_eq!(day("5/2/20"), Some(43953.0));
+        assert_eq!(day("05/02/2020 00:00"), Some(43953.0));
+        assert_eq!(day("2020-05-02T10:30"), Some(43953.0));
+        assert_eq!(day("May 2"), None);
+        assert_eq!(day("13/2/20"), None);
+    }
+}